@@ -0,0 +1,64 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use oblivious_transfer::net::{encrypt, into_key, MessageState, Suite, UserMessage};
+use p256::elliptic_curve::{ops::MulByGenerator, Field};
+use p256::{ProjectivePoint, Scalar};
+use rand::thread_rng;
+
+fn message(text: &str) -> UserMessage {
+    UserMessage::try_from(text.to_string()).unwrap()
+}
+
+/// Full base OT transfer: both parties' scalars, four point multiplications and the AES steps.
+fn transfer(c: &mut Criterion) {
+    let (m0, m1) = (message("first message"), message("second message"));
+    c.bench_function("base OT transfer", |b| {
+        b.iter(|| {
+            let (point, sender) = MessageState::send_message(m0.clone(), m1.clone(), None);
+            let (response, receiver) = MessageState::on_greeting(point, true);
+            let (e0, e1, _) = sender.on_response(Suite::P256Aes256Cbc, response).unwrap();
+            black_box(receiver.on_messages(Suite::P256Aes256Cbc, e0, e1).unwrap())
+        })
+    });
+}
+
+/// Curve steps only: the sender's greeting and the receiver's response.
+fn curve(c: &mut Criterion) {
+    let (m0, m1) = (message("first message"), message("second message"));
+    c.bench_function("curve step", |b| {
+        b.iter(|| {
+            let (point, sender) = MessageState::send_message(m0.clone(), m1.clone(), None);
+            black_box((MessageState::on_greeting(point, true), sender))
+        })
+    });
+}
+
+/// Fixed-base multiplication of the generator against the generic variable-base one.
+fn generator(c: &mut Criterion) {
+    let scalar = Scalar::random(thread_rng());
+    c.bench_function("generator mul_by_generator", |b| {
+        b.iter(|| ProjectivePoint::mul_by_generator(black_box(&scalar)))
+    });
+    c.bench_function("generator variable base", |b| {
+        b.iter(|| ProjectivePoint::GENERATOR * black_box(scalar))
+    });
+}
+
+/// Symmetric step of the sender: key derivation and encryption of both messages.
+fn aes(c: &mut Criterion) {
+    let (k0, k1) = (
+        ProjectivePoint::GENERATOR,
+        ProjectivePoint::GENERATOR * Scalar::from(2u64),
+    );
+    c.bench_function("AES step", |b| {
+        b.iter(|| {
+            let (key0, key1) = (into_key(k0), into_key(k1));
+            black_box((
+                encrypt(&key0, b"first message"),
+                encrypt(&key1, b"second message"),
+            ))
+        })
+    });
+}
+
+criterion_group!(benches, transfer, curve, generator, aes);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+//! Feeds arbitrary bytes to the protocol parser, which must reject them with an error, never panic.
+//! Run with `cargo +nightly fuzz run message`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = oblivious_transfer::net::parse_frame(data);
+});
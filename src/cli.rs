@@ -0,0 +1,192 @@
+use std::net::{IpAddr, SocketAddr};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+
+use crate::net::{self_test, Discovery, Event, NetworkHost, NetworkOptions, UserMessage, Username};
+use crate::UiContext;
+
+static POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Oblivious transfer chat. Starts the graphical interface when no command is given.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Headless commands driving the network host without a window.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Broadcast presence and list peers discovered within the wait time.
+    Peers {
+        #[command(flatten)]
+        host: HostArgs,
+    },
+    /// Broadcast presence and print messages received within the wait time.
+    Listen {
+        #[command(flatten)]
+        host: HostArgs,
+        /// Message of each incoming pair to receive, 0 or 1. Fixed for the whole run, so a scripted
+        /// demo receives the same message every time.
+        #[arg(
+            long,
+            env = "OT_CHOICE",
+            default_value_t = 0,
+            value_parser = clap::value_parser!(u8).range(0..=1)
+        )]
+        choice: u8,
+    },
+    /// Send a message pair to a peer, letting it obliviously receive one of them. Fails unless it
+    /// is delivered within the wait time.
+    Send {
+        #[command(flatten)]
+        host: HostArgs,
+        /// Address of the receiving peer.
+        #[arg(long)]
+        to: SocketAddr,
+        /// First message.
+        #[arg(long)]
+        m0: String,
+        /// Second message.
+        #[arg(long)]
+        m1: String,
+    },
+    /// Broadcast a plaintext announcement to every peer, outside of any transfer.
+    Announce {
+        #[command(flatten)]
+        host: HostArgs,
+        /// Text of the announcement.
+        #[arg(long)]
+        text: String,
+    },
+    /// Check the cryptography against known answers, catching a broken build.
+    SelfTest,
+}
+
+/// Arguments shared by all headless commands.
+#[derive(Debug, clap::Args)]
+pub struct HostArgs {
+    /// Username announced to other peers.
+    #[arg(long)]
+    name: String,
+    /// UDP port to bind and broadcast on.
+    #[arg(long, default_value_t = 12345)]
+    port: u16,
+    /// Local address to bind, pinning the interface used. Listens on all interfaces by default.
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: IpAddr,
+    /// Seconds to wait for network events before exiting.
+    #[arg(long, default_value_t = 3)]
+    wait: u64,
+    /// Leave the username out of broadcasts, sending it encrypted only to peers that answer.
+    #[arg(long)]
+    hide_name: bool,
+    /// Bind one of the next few ports if the port is in use, instead of failing.
+    #[arg(long)]
+    port_fallback: bool,
+}
+
+/// Run a headless command.
+pub fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Peers { host } => {
+            let mut network_host = connect(&host)?;
+            poll_events(&mut network_host, host.wait, |_| {});
+            for peer in network_host.peers() {
+                println!("{peer}");
+            }
+            network_host.disconnect()?;
+        }
+        Command::Listen { host, choice } => {
+            let mut network_host = connect(&host)?;
+            network_host.set_default_choice(Some(choice == 1))?;
+            poll_events(&mut network_host, host.wait, |event| match event {
+                Event::Message(address, message, _) => println!("{address}: {message}"),
+                Event::Announcement(address, text) => println!("{address} announces: {text}"),
+                Event::Transcript(address, hash) => {
+                    println!("Transcript hash with {address}: {}", hex::encode(hash))
+                }
+                Event::Sas(address, sas) => {
+                    println!("Short authentication string with {address}: {sas}")
+                }
+                _ => {}
+            });
+            network_host.disconnect()?;
+        }
+        Command::Send { host, to, m0, m1 } => {
+            let mut network_host = connect(&host)?;
+            let m0 = UserMessage::try_from(m0)?;
+            let m1 = UserMessage::try_from(m1)?;
+            network_host.send(m0, m1, to, None)?;
+            let mut delivered = false;
+            poll_events(&mut network_host, host.wait, |event| match event {
+                Event::Delivered(_) => {
+                    println!("Delivered to {to}");
+                    delivered = true;
+                }
+                Event::Transcript(_, hash) => println!("Transcript hash: {}", hex::encode(hash)),
+                Event::Sas(_, sas) => println!("Short authentication string: {sas}"),
+                _ => {}
+            });
+            network_host.disconnect()?;
+            // A script relies on the exit status, a failed or unanswered transfer is an error.
+            if !delivered {
+                return Err(format!("Message to {to} was not delivered").into());
+            }
+        }
+        Command::Announce { host, text } => {
+            let mut network_host = connect(&host)?;
+            network_host.announce(UserMessage::try_from(text)?)?;
+            poll_events(&mut network_host, host.wait, |_| {});
+            network_host.disconnect()?;
+        }
+        Command::SelfTest => {
+            self_test()?;
+            println!("Self-test passed");
+        }
+    }
+
+    Ok(())
+}
+
+fn connect(host: &HostArgs) -> Result<NetworkHost, Box<dyn std::error::Error>> {
+    let name = Username::new(host.name.clone())?;
+    Ok(NetworkHost::new(
+        UiContext::default(),
+        name,
+        SocketAddr::new(host.bind, host.port),
+        NetworkOptions {
+            hide_name: host.hide_name,
+            port_fallback: host.port_fallback,
+            discovery: Discovery::Broadcast,
+            ..NetworkOptions::default()
+        },
+    ))
+}
+
+/// Poll network events for the given number of seconds, printing errors to stderr.
+fn poll_events(network_host: &mut NetworkHost, wait: u64, mut on_event: impl FnMut(&Event)) {
+    let deadline = Instant::now() + Duration::from_secs(wait);
+    while Instant::now() < deadline {
+        match network_host.poll_event() {
+            Some(event) => {
+                match &event {
+                    Event::Error(error)
+                    | Event::SendFailed(_, error)
+                    | Event::ResolveFailed(_, error)
+                    | Event::DiscoveryUnavailable(error) => eprintln!("{error}"),
+                    Event::Rejected(address, reason) => eprintln!("{address} ignores us: {reason}"),
+                    Event::DecryptFailed(address) => {
+                        eprintln!("Couldn't decrypt message from {address}")
+                    }
+                    _ => {}
+                }
+                on_event(&event);
+            }
+            None => sleep(POLL_INTERVAL),
+        }
+    }
+}
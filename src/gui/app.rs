@@ -1,92 +1,526 @@
 use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Instant;
 
-use eframe::egui::{Align2, CentralPanel, Pos2, SidePanel, TopBottomPanel, WidgetText};
+use eframe::egui::{
+    Align, Align2, CentralPanel, Layout, Pos2, SidePanel, TopBottomPanel, Ui, WidgetText,
+};
 use eframe::glow::Context;
-use eframe::{egui, Frame};
+use eframe::{egui, CreationContext, Frame, Storage};
 use egui_toast::{Toast, ToastKind, ToastOptions, Toasts};
+use notify_rust::Notification;
+use serde::{Deserialize, Serialize};
 use tracing::error;
 
-use crate::net::{Event, Peer};
+use crate::net::{self_test, Event, NetworkError, Peer, UserMessage, Username};
 
-use super::{MessagePanel, PeerPanel, PeerPanelAction, TopPanel};
+use super::{
+    Blocklist, MessagePanel, MessagePanelAction, PeerPanel, PeerPanelAction, SendRequest, Settings,
+    TopPanel,
+};
+
+static MAX_EVENTS_PER_FRAME: usize = 64;
+static NOTIFICATION_PREVIEW: usize = 100; // Characters of a message shown in a notification
 
 /// Gui application.
 pub struct App {
+    profiles: Vec<Profile>,
+    active: usize,
+    toast: Toasts,
+    settings: Settings,
+    settings_open: bool,
+    last_peer: Option<LastPeer>,
+    resume: Option<Peer>,
+}
+
+/// Peer messages were last exchanged with, persisted to offer resuming the chat on startup.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LastPeer {
+    address: SocketAddr,
+    name: Option<String>,
+}
+
+impl LastPeer {
+    /// Key of the last peer in the eframe storage.
+    const KEY: &'static str = "last_peer";
+
+    fn new(peer: &Peer) -> Self {
+        Self {
+            address: peer.address(),
+            name: peer.name().map(ToString::to_string),
+        }
+    }
+
+    fn peer(&self) -> Peer {
+        match self.name.clone().map(Username::new) {
+            Some(Ok(name)) => Peer::new_with_name(self.address, name),
+            _ => Peer::new(self.address),
+        }
+    }
+}
+
+/// Network identity with its own host, peers and chats.
+struct Profile {
     message_panel: MessagePanel,
     peer_panel: PeerPanel,
     top_panel: TopPanel,
-    toast: Toasts,
+    last_heartbeat: Instant,
+    blocklist: Option<Blocklist>,
+    export_secrets: Option<bool>,
 }
 
-impl Default for App {
-    fn default() -> Self {
+impl App {
+    /// Create the application, restoring the settings from the previous run.
+    pub fn new(cc: &CreationContext) -> Self {
+        let settings: Settings = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, Settings::KEY))
+            .unwrap_or_default();
+        settings.apply(&cc.egui_ctx);
+        let last_peer: Option<LastPeer> = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, LastPeer::KEY));
+        let mut toast = Toasts::new().anchor(Align2::RIGHT_BOTTOM, Pos2::new(-10.0, -10.0));
+        if settings.self_test {
+            match self_test() {
+                Ok(()) => show_toast(&mut toast, ToastKind::Success, "Crypto self-test passed"),
+                Err(error) => show_error(&mut toast, error),
+            }
+        }
+
         Self {
-            message_panel: Default::default(),
-            peer_panel: Default::default(),
-            top_panel: Default::default(),
-            toast: Toasts::new().anchor(Align2::RIGHT_BOTTOM, Pos2::new(-10.0, -10.0)),
+            profiles: vec![Profile::new(
+                TopPanel::new(settings.username.clone()),
+                settings.show_demo,
+            )],
+            active: 0,
+            toast,
+            settings,
+            settings_open: false,
+            resume: last_peer.as_ref().map(LastPeer::peer),
+            last_peer,
+        }
+    }
+
+    /// Draw the profile switcher, only shown once there is more than one profile.
+    fn profile_switcher(&mut self, ui: &mut Ui) {
+        if self.profiles.len() > 1 {
+            egui::ComboBox::from_id_source("profile")
+                .selected_text(self.profiles[self.active].name(self.active))
+                .show_ui(ui, |ui| {
+                    for (index, profile) in self.profiles.iter().enumerate() {
+                        ui.selectable_value(&mut self.active, index, profile.name(index));
+                    }
+                });
+            if ui.button("✖").on_hover_text("Remove profile").clicked() {
+                let mut profile = self.profiles.remove(self.active);
+                profile.top_panel.on_exit();
+                self.active = self.active.min(self.profiles.len() - 1);
+            }
+            ui.separator();
+        }
+        let add = ui
+            .button("➕")
+            .on_hover_text("Add a profile with its own name and port");
+        if add.clicked() {
+            // Each profile needs its own socket, so offer the next free port by default.
+            let port = self.settings.port.wrapping_add(self.profiles.len() as u16);
+            let top_panel = TopPanel::with_port(String::new(), port);
+            self.profiles
+                .push(Profile::new(top_panel, self.settings.show_demo));
+            self.active = self.profiles.len() - 1;
         }
     }
 }
 
-impl eframe::App for App {
-    fn update(&mut self, ctx: &egui::Context, _: &mut Frame) {
-        TopBottomPanel::top("top_panel").show(ctx, |ui| {
-            if let Err(err) = self.top_panel.draw(ui) {
-                show_error(&mut self.toast, err);
+impl Profile {
+    fn new(top_panel: TopPanel, demo: bool) -> Self {
+        Self {
+            message_panel: MessagePanel::new(demo),
+            peer_panel: Default::default(),
+            top_panel,
+            last_heartbeat: Instant::now(),
+            blocklist: None,
+            export_secrets: None,
+        }
+    }
+
+    /// Name shown in the profile switcher.
+    fn name(&self, index: usize) -> String {
+        match self.top_panel.name() {
+            "" => format!("Profile {}", index + 1),
+            name => name.to_string(),
+        }
+    }
+
+    /// Send heartbeats and handle network events, also while the profile is not shown.
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        settings: &Settings,
+        toast: &mut Toasts,
+        last_peer: &mut Option<LastPeer>,
+    ) {
+        self.message_panel
+            .set_max_message_length(settings.max_message_length);
+        self.message_panel.set_decoy(&settings.decoy);
+        self.message_panel.set_show_points(settings.show_points);
+        self.message_panel.set_debug_export(settings.debug_export);
+
+        let Some(client) = self.top_panel.get_network_host() else {
+            self.peer_panel.clear_peers();
+            self.message_panel.close_all();
+            self.blocklist = None;
+            self.export_secrets = None;
+            return;
+        };
+
+        // Sent whenever it changes, and to every new connection.
+        if self.blocklist.as_ref() != Some(&settings.blocklist) {
+            let blocklist = &settings.blocklist;
+            match client.set_blocklist(blocklist.addresses.clone(), blocklist.usernames()) {
+                Err(NetworkError::ChannelFull) => ctx.request_repaint(),
+                result => {
+                    if let Err(err) = result {
+                        show_error(toast, err);
+                    }
+                    self.blocklist = Some(blocklist.clone());
+                }
             }
-        });
+        }
 
-        let client = match self.top_panel.get_network_host() {
-            Some(client) => client,
-            None => {
-                self.peer_panel.clear_peers();
-                self.message_panel.close_all();
-                CentralPanel::default().show(ctx, |ui| self.message_panel.show(ui));
-                return;
+        if self.export_secrets != Some(settings.debug_export) {
+            match client.export_secrets(settings.debug_export) {
+                Err(NetworkError::ChannelFull) => ctx.request_repaint(),
+                result => {
+                    if let Err(err) = result {
+                        show_error(toast, err);
+                    }
+                    self.export_secrets = Some(settings.debug_export);
+                }
             }
-        };
+        }
+
+        if let Some(interval) = settings.heartbeat() {
+            let elapsed = self.last_heartbeat.elapsed();
+            if elapsed >= interval {
+                match client.heartbeat() {
+                    // Retried on the next frame, once the task has caught up.
+                    Err(NetworkError::ChannelFull) => ctx.request_repaint(),
+                    result => {
+                        if let Err(err) = result {
+                            show_error(toast, err);
+                        }
+                        self.last_heartbeat = Instant::now();
+                        ctx.request_repaint_after(interval);
+                    }
+                }
+            } else {
+                ctx.request_repaint_after(interval - elapsed);
+            }
+        }
+
+        for processed in 0.. {
+            if processed == MAX_EVENTS_PER_FRAME {
+                // Leave the rest for the next frame so rendering is not starved.
+                ctx.request_repaint();
+                break;
+            }
+
+            let Some(event) = client.poll_event() else {
+                break;
+            };
 
-        while let Some(event) = client.poll_event() {
             match event {
-                Event::Error(error) => show_error(&mut self.toast, error),
+                Event::Error(error) => {
+                    self.on_session_error(&error);
+                    show_error(toast, error);
+                }
+                Event::LocalAddress(address) => {
+                    show_toast(toast, ToastKind::Info, format!("Listening on {address}"));
+                }
+                Event::DiscoveryUnavailable(error) => {
+                    error!("{error}");
+                    show_toast(
+                        toast,
+                        ToastKind::Warning,
+                        "Auto-discovery unavailable; add peers manually",
+                    );
+                    self.peer_panel.focus_add_field();
+                }
                 Event::Connected(peer) => self.peer_panel.add_peer(peer),
+                Event::PeerRenamed(addr, name) => {
+                    let peer = Peer::new_with_name(addr, name);
+                    self.peer_panel.add_peer(peer.clone());
+                    self.message_panel.on_peer_renamed(&peer);
+                }
                 Event::Disconnected(address) => self.peer_panel.remove_peer(&address),
-                Event::Message(addr, message) => {
+                Event::Refreshed => self
+                    .peer_panel
+                    .on_refreshed(|addr| client.last_seen(addr).is_some()),
+                Event::Message(addr, message, verified) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel
+                        .on_message(&peer, message.clone(), verified);
+                    self.message_panel.repaint_windows(ctx, &addr);
+                    *last_peer = Some(LastPeer::new(&peer));
+                    let focused = ctx.input(|input| input.viewport().focused);
+                    if settings.notifications && focused == Some(false) {
+                        notify(&peer, &message);
+                    }
+                    show_toast(toast, ToastKind::Success, message);
+                }
+                Event::Announcement(addr, text) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    show_toast(toast, ToastKind::Info, format!("📢 {peer}: {text}"));
+                }
+                Event::DecryptFailed(addr) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel.on_decrypt_failed(&peer);
+                    self.message_panel.repaint_windows(ctx, &addr);
+                }
+                Event::Delivered(id) => self.message_panel.on_delivered(id),
+                Event::Transcript(addr, hash) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel.on_transcript(&peer, hash);
+                }
+                Event::Sas(addr, sas) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel.on_sas(&peer, sas);
+                }
+                Event::Points(addr, greet, response) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel.on_points(&peer, greet, response);
+                }
+                Event::Ciphertext(addr, m0, m1) => {
                     let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
-                    self.message_panel.on_message(&peer, message.clone());
-                    show_toast(&mut self.toast, ToastKind::Success, message);
+                    self.message_panel.on_ciphertext(&peer, &m0, &m1);
+                }
+                Event::Scalar(addr, scalar) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel.on_scalar(&peer, &scalar);
+                }
+                Event::ChoiceRequested(addr) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    self.message_panel.repaint_windows(ctx, &addr);
+                    match self.message_panel.on_choice_requested(&peer) {
+                        Some(c) => {
+                            if let Err(err) = client.choose(addr, c) {
+                                show_error(toast, err);
+                            }
+                        }
+                        None => show_toast(
+                            toast,
+                            ToastKind::Info,
+                            format!("{peer} is sending a message pair, choose one in the chat"),
+                        ),
+                    }
+                }
+                Event::Rejected(addr, reason) => {
+                    let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                    show_toast(
+                        toast,
+                        ToastKind::Warning,
+                        format!("{peer} ignores us: {reason}"),
+                    );
+                }
+                Event::Pong(addr, rtt) => self.peer_panel.on_pong(addr, rtt),
+                Event::Resolved(host, address) => self.peer_panel.on_resolved(&host, address),
+                Event::ResolveFailed(host, error) => {
+                    self.peer_panel.on_resolve_failed(&host);
+                    show_error(toast, error);
+                }
+                Event::SendFailed(id, error) => {
+                    self.message_panel.on_delivery_failed(id);
+                    self.on_session_error(&error);
+                    show_error(toast, error);
                 }
             }
         }
+    }
 
-        SidePanel::left("peer_panel").show(ctx, |ui| match self.peer_panel.draw(ui) {
-            PeerPanelAction::PeerClicked(peer) => self.message_panel.open_tile(peer.clone()),
-            PeerPanelAction::RefreshPeers => {
-                if let Err(err) = client.refresh_hosts() {
-                    show_error(&mut self.toast, err);
+    /// Record an error about a peer in its session, for the debug export.
+    fn on_session_error(&mut self, error: &NetworkError) {
+        if let Some(addr) = error.address() {
+            let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+            self.message_panel.on_error(&peer, error);
+        }
+    }
+
+    /// Draw the peer list and the chats of the profile, offering to resume the chat with `resume`.
+    /// Peers the user blocks are added to `blocklist`.
+    fn show(
+        &mut self,
+        ctx: &egui::Context,
+        toast: &mut Toasts,
+        last_peer: &mut Option<LastPeer>,
+        resume: &mut Option<Peer>,
+        blocklist: &mut Blocklist,
+    ) {
+        let Some(client) = self.top_panel.get_network_host() else {
+            CentralPanel::default().show(ctx, |ui| self.message_panel.show(ui));
+            return;
+        };
+
+        SidePanel::left("peer_panel").show(ctx, |ui| {
+            if let Some(peer) = resume.clone() {
+                ui.horizontal(|ui| {
+                    if ui.button(format!("Resume chat with {peer}")).clicked() {
+                        // Greet the peer again, it is not known after a restart.
+                        if let Err(err) = client.add_peer(peer.address()) {
+                            show_error(toast, err);
+                        }
+                        self.peer_panel.add_peer(peer.clone());
+                        self.message_panel.open_tile(peer);
+                        *resume = None;
+                    }
+                    if ui.small_button("✖").on_hover_text("Dismiss").clicked() {
+                        *resume = None;
+                    }
+                });
+                ui.separator();
+            }
+            match self.peer_panel.draw(ui, |addr| client.last_seen(addr)) {
+                PeerPanelAction::PeerClicked(peer) => self.message_panel.open_tile(peer.clone()),
+                PeerPanelAction::PeerAdded(address) => {
+                    if let Err(err) = client.add_peer(address) {
+                        show_error(toast, err);
+                    }
+                }
+                PeerPanelAction::NewChat(address) => {
+                    // Greet the peer so its name shows up once it answers.
+                    if let Err(err) = client.add_peer(address) {
+                        show_error(toast, err);
+                    }
+                    self.message_panel.open_tile(Peer::new(address));
+                }
+                PeerPanelAction::PeerResolve(host) => {
+                    if let Err(err) = client.add_peer_by_name(host) {
+                        show_error(toast, err);
+                    }
+                }
+                PeerPanelAction::Ping(address) => {
+                    if let Err(err) = client.ping(address) {
+                        show_error(toast, err);
+                    }
+                }
+                PeerPanelAction::Block(peer) => blocklist.block(peer),
+                PeerPanelAction::Announce(text) => match UserMessage::try_from(text) {
+                    Ok(text) => {
+                        if let Err(err) = client.announce(text) {
+                            show_error(toast, err);
+                        }
+                    }
+                    Err(err) => show_error(toast, err),
+                },
+                PeerPanelAction::RefreshPeers => {
+                    if let Err(err) = client.refresh_hosts() {
+                        show_error(toast, err);
+                    }
                 }
+                PeerPanelAction::None => {}
             }
-            PeerPanelAction::None => {}
         });
 
         let frame = egui::Frame::central_panel(&ctx.style())
             .outer_margin(egui::Margin::default())
             .inner_margin(egui::Margin::default());
 
-        CentralPanel::default().frame(frame).show(ctx, |ui| {
-            if let Some((addr, m0, m1, a)) = self.message_panel.show(ui) {
-                if let Err(err) = client.send(m0, m1, addr, a) {
+        CentralPanel::default()
+            .frame(frame)
+            .show(ctx, |ui| match self.message_panel.show(ui) {
+                Some(MessagePanelAction::Send(SendRequest { addr, m0, m1, a })) => {
+                    match client.send(m0, m1, addr, a) {
+                        Ok(id) => {
+                            self.message_panel.on_sent(&addr, id);
+                            let peer = self.peer_panel.get_peer(&addr).unwrap_or(Peer::new(addr));
+                            *last_peer = Some(LastPeer::new(&peer));
+                        }
+                        Err(err) => {
+                            self.message_panel.on_send_failed(&addr);
+                            show_error(toast, err);
+                        }
+                    }
+                }
+                Some(MessagePanelAction::Choose(addr, c)) => {
+                    if let Err(err) = client.choose(addr, c) {
+                        show_error(toast, err);
+                    }
+                }
+                Some(MessagePanelAction::Closed(addr)) => {
+                    if let Err(err) = client.cancel_peer(addr) {
+                        show_error(toast, err);
+                    }
+                }
+                Some(MessagePanelAction::Block(peer)) => blocklist.block(&peer),
+                Some(MessagePanelAction::CancelSend(id)) => {
+                    if let Err(err) = client.cancel_send(id) {
+                        show_error(toast, err);
+                    }
+                }
+                None => {}
+            });
+    }
+}
+
+impl eframe::App for App {
+    fn update(&mut self, ctx: &egui::Context, _: &mut Frame) {
+        TopBottomPanel::top("top_panel").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                self.profile_switcher(ui);
+                let top_panel = &mut self.profiles[self.active].top_panel;
+                if let Err(err) = top_panel.draw(ui, &self.settings) {
                     show_error(&mut self.toast, err);
                 }
-            }
+                ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                    if ui.button("⚙").on_hover_text("Settings").clicked() {
+                        self.settings_open = !self.settings_open;
+                    }
+                    let demo = ui.button("🎓").on_hover_text("Open the cryptography demo");
+                    if demo.clicked() {
+                        self.profiles[self.active].message_panel.open_demo();
+                    }
+                });
+            });
         });
+        self.settings.show(ctx, &mut self.settings_open);
+
+        for profile in &mut self.profiles {
+            profile.update(ctx, &self.settings, &mut self.toast, &mut self.last_peer);
+        }
+        self.profiles[self.active].show(
+            ctx,
+            &mut self.toast,
+            &mut self.last_peer,
+            &mut self.resume,
+            &mut self.settings.blocklist,
+        );
 
         self.toast.show(ctx);
     }
 
+    fn save(&mut self, storage: &mut dyn Storage) {
+        eframe::set_value(storage, Settings::KEY, &self.settings);
+        if let Some(last_peer) = &self.last_peer {
+            eframe::set_value(storage, LastPeer::KEY, last_peer);
+        }
+    }
+
     fn on_exit(&mut self, _: Option<&Context>) {
-        self.top_panel.on_exit();
+        for profile in &mut self.profiles {
+            profile.top_panel.on_exit();
+        }
+    }
+}
+
+fn notify(peer: &Peer, message: &str) {
+    let preview: String = message.chars().take(NOTIFICATION_PREVIEW).collect();
+    let result = Notification::new()
+        .summary(&format!("Message from {peer}"))
+        .body(&preview)
+        .show();
+    if let Err(err) = result {
+        error!("Failed to show notification: {err}");
     }
 }
 
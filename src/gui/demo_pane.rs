@@ -1,11 +1,23 @@
+use aes::cipher::{KeyIvInit, StreamCipher};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
 use eframe::egui::{self, FontId, TextBuffer, TextEdit, Ui, Widget};
+use libaes::Cipher;
 use p256::elliptic_curve::generic_array::GenericArray;
 use p256::elliptic_curve::point::AffineCoordinates;
-use p256::elliptic_curve::sec1::ToEncodedPoint;
 use p256::elliptic_curve::{Field, PrimeField};
 use p256::ProjectivePoint;
 use rand::thread_rng;
-use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::net::{decrypt, encrypt, into_key};
+
+/// Canonical example of the demo. The scalars are tiny, so the points can be checked by hand.
+static EXAMPLE_M0: &str = "Attack at dawn";
+static EXAMPLE_M1: &str = "Retreat at dusk";
+static EXAMPLE_A: &str = "07";
+static EXAMPLE_B: &str = "0b";
+static EXAMPLE_C: bool = true;
 
 #[derive(Debug, Eq, PartialEq)]
 enum C {
@@ -13,6 +25,55 @@ enum C {
     C1,
 }
 
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// Symmetric cipher mode of the demo. CBC is the mode used by the network.
+/// The IV or nonce is taken from the key, as every key encrypts a single message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    Cbc,
+    Ctr,
+    Gcm,
+}
+
+impl Mode {
+    fn encrypt(self, key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+        match self {
+            Mode::Cbc => encrypt(key, data),
+            Mode::Ctr => {
+                let mut buffer = data.to_vec();
+                Aes256Ctr::new(key.into(), key[..16].into()).apply_keystream(&mut buffer);
+                buffer
+            }
+            Mode::Gcm => Aes256Gcm::new(key.into())
+                .encrypt(Nonce::from_slice(&key[..12]), data)
+                .expect("message fits in a single GCM encryption"),
+        }
+    }
+
+    fn decrypt(self, key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        match self {
+            Mode::Cbc => Ok(decrypt(key, data)),
+            Mode::Ctr => Ok(self.encrypt(key, data)),
+            Mode::Gcm => Aes256Gcm::new(key.into())
+                .decrypt(Nonce::from_slice(&key[..12]), data)
+                .map_err(|_| "Authentication failed"),
+        }
+    }
+
+    /// Decrypt without checking the padding, showing what a wrong key really yields.
+    fn decrypt_raw(self, key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        match self {
+            Mode::Cbc => {
+                let mut cipher = Cipher::new_256(key);
+                cipher.set_auto_padding(false);
+                Ok(cipher.cbc_decrypt(key, data))
+            }
+            Mode::Ctr | Mode::Gcm => self.decrypt(key, data),
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub(super) struct DemoPane {
     m0: String,
@@ -20,8 +81,9 @@ pub(super) struct DemoPane {
     a: String,
     b: String,
     c: C,
-    a_scalar: p256::Scalar,
-    b_scalar: p256::Scalar,
+    mode: Mode,
+    a_scalar: Zeroizing<p256::Scalar>,
+    b_scalar: Zeroizing<p256::Scalar>,
     a_point: ProjectivePoint,
     b_point: ProjectivePoint,
     e0: Vec<u8>,
@@ -29,7 +91,41 @@ pub(super) struct DemoPane {
 }
 
 impl DemoPane {
+    /// Create a demo from preset messages, hex scalars and choice. The scalars are parsed when
+    /// drawn, like the ones typed in.
+    pub(super) fn new(m0: &str, m1: &str, a: &str, b: &str, c: bool) -> Self {
+        Self {
+            m0: m0.to_string(),
+            m1: m1.to_string(),
+            a: a.to_string(),
+            b: b.to_string(),
+            c: if c { C::C1 } else { C::C0 },
+            mode: Mode::Cbc,
+            a_scalar: Zeroizing::new(p256::Scalar::ZERO),
+            b_scalar: Zeroizing::new(p256::Scalar::ZERO),
+            a_point: ProjectivePoint::IDENTITY,
+            b_point: ProjectivePoint::IDENTITY,
+            e0: Vec::new(),
+            e1: Vec::new(),
+        }
+    }
+
+    /// Create the demo with the canonical example, the same in every session.
+    pub(super) fn example() -> Self {
+        Self::new(EXAMPLE_M0, EXAMPLE_M1, EXAMPLE_A, EXAMPLE_B, EXAMPLE_C)
+    }
+
     pub(super) fn draw(&mut self, ui: &mut Ui) {
+        let load = ui.button("Load example");
+        if load
+            .on_hover_text("Fill in a fixed, well-known example")
+            .clicked()
+        {
+            *self = Self {
+                mode: self.mode,
+                ..Self::example()
+            };
+        }
         ui.collapsing("Alice", |ui| {
             egui::Grid::new("alice").num_columns(2).show(ui, |ui| {
                 ui.label("m0:");
@@ -47,6 +143,13 @@ impl DemoPane {
                     text_field(&mut self.a).ui(ui);
                 });
                 ui.end_row();
+                ui.label("Cipher:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.mode, Mode::Cbc, "AES-CBC");
+                    ui.radio_value(&mut self.mode, Mode::Ctr, "AES-CTR");
+                    ui.radio_value(&mut self.mode, Mode::Gcm, "AES-GCM");
+                });
+                ui.end_row();
             });
         });
         ui.collapsing("Bob", |ui| {
@@ -87,8 +190,8 @@ impl DemoPane {
 
             let abytes: [u8; 32] = abytes.try_into().unwrap();
             let abytes = GenericArray::from_slice(&abytes);
-            self.a_scalar = p256::Scalar::from_repr(*abytes).unwrap();
-            self.a_point = ProjectivePoint::GENERATOR * self.a_scalar;
+            self.a_scalar = Zeroizing::new(p256::Scalar::from_repr(*abytes).unwrap());
+            self.a_point = ProjectivePoint::GENERATOR * *self.a_scalar;
 
             egui::Grid::new("a_to_b_1").num_columns(2).show(ui, |ui| {
                 let a_point = self.a_point.to_affine();
@@ -117,14 +220,14 @@ impl DemoPane {
                 return;
             }
             let bbytes = GenericArray::from_slice(&bbytes);
-            self.b_scalar = p256::Scalar::from_repr(*bbytes).unwrap();
+            self.b_scalar = Zeroizing::new(p256::Scalar::from_repr(*bbytes).unwrap());
 
             let gen = ProjectivePoint::GENERATOR;
 
             self.b_point = if self.c == C::C0 {
-                gen * self.b_scalar
+                gen * *self.b_scalar
             } else {
-                self.a_point + gen * self.b_scalar
+                self.a_point + gen * *self.b_scalar
             };
 
             egui::Grid::new("b_to_a_1").num_columns(2).show(ui, |ui| {
@@ -138,20 +241,14 @@ impl DemoPane {
             });
         });
         ui.collapsing("Oblivious Transfer Protocol (Alice -> Bob) ", |ui| {
-            let k_0_p = self.b_point * self.a_scalar;
-            let k_1_p = (self.b_point - self.a_point) * self.a_scalar;
+            let k_0_p = self.b_point * *self.a_scalar;
+            let k_1_p = (self.b_point - self.a_point) * *self.a_scalar;
 
-            let k_0 = Sha256::digest(k_0_p.to_encoded_point(false).as_bytes())
-                .as_slice()
-                .try_into()
-                .unwrap();
-            let k_1 = Sha256::digest(k_1_p.to_encoded_point(false).as_bytes())
-                .as_slice()
-                .try_into()
-                .unwrap();
+            let k_0 = into_key(k_0_p);
+            let k_1 = into_key(k_1_p);
 
-            self.e0 = libaes::Cipher::new_256(&k_0).cbc_encrypt(&k_0, self.m0.as_bytes());
-            self.e1 = libaes::Cipher::new_256(&k_1).cbc_encrypt(&k_1, self.m1.as_bytes());
+            self.e0 = self.mode.encrypt(&k_0, self.m0.as_bytes());
+            self.e1 = self.mode.encrypt(&k_1, self.m1.as_bytes());
 
             let e0 = hex::encode(&self.e0);
             let e1 = hex::encode(&self.e1);
@@ -171,24 +268,36 @@ impl DemoPane {
                 ui.end_row();
             });
         });
-        ui.collapsing("Oblivious Transfer Protocol (Bob)", |ui| {
-            let k_c_p = self.a_point * self.b_scalar;
-            let k_c = Sha256::digest(k_c_p.to_encoded_point(false).as_bytes())
-                .as_slice()
-                .try_into()
-                .unwrap();
-            let e_c = if self.c == C::C0 { &self.e0 } else { &self.e1 };
-            let m_c = libaes::Cipher::new_256(&k_c).cbc_decrypt(&k_c, e_c);
+        ui.collapsing("Bob's view", |ui| {
+            ui.label("Only what Bob receives and computes: both ciphertexts and his single key.");
+            let k_c_p = self.a_point * *self.b_scalar;
+            let k_c = into_key(k_c_p);
+            let (e_c, e_other) = match self.c {
+                C::C0 => (&self.e0, &self.e1),
+                C::C1 => (&self.e1, &self.e0),
+            };
+            let m_c = self.mode.decrypt(&k_c, e_c);
+            let m_other = self.mode.decrypt_raw(&k_c, e_other);
 
             egui::Grid::new("b_1").num_columns(2).show(ui, |ui| {
+                ui.label("e0:");
+                ui.label(hex::encode(&self.e0));
+                ui.end_row();
+                ui.label("e1:");
+                ui.label(hex::encode(&self.e1));
+                ui.end_row();
                 ui.label("k_c:");
                 ui.label(hex::encode(k_c));
                 ui.end_row();
-                ui.label("e_c:");
-                ui.label(hex::encode(e_c));
-                ui.end_row();
                 ui.label("m_c:");
-                ui.label(String::from_utf8(m_c).unwrap());
+                ui.label(plaintext(m_c));
+                ui.end_row();
+                ui.label("e_(1-c) with k_c:")
+                    .on_hover_text("The key of the other message is never known to Bob");
+                match m_other {
+                    Ok(bytes) => ui.label(hex::encode(bytes)),
+                    Err(error) => ui.label(error),
+                };
                 ui.end_row();
             });
         });
@@ -202,19 +311,14 @@ impl Default for DemoPane {
         let b = p256::Scalar::random(thread_rng());
         let bhex = format!("{:x}", b.to_bytes());
 
-        Self {
-            m0: String::new(),
-            m1: String::new(),
-            a: ahex,
-            b: bhex,
-            c: C::C0,
-            a_scalar: a,
-            b_scalar: b,
-            a_point: ProjectivePoint::IDENTITY,
-            b_point: ProjectivePoint::IDENTITY,
-            e0: Vec::new(),
-            e1: Vec::new(),
-        }
+        Self::new("", "", &ahex, &bhex, false)
+    }
+}
+
+fn plaintext(decrypted: Result<Vec<u8>, &str>) -> String {
+    match decrypted {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(error) => error.to_string(),
     }
 }
 
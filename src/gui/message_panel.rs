@@ -1,50 +1,315 @@
 use std::collections::hash_map::Entry;
 use std::net::SocketAddr;
+use std::time::SystemTime;
 
 use eframe::egui::ahash::HashMap;
 use eframe::egui::{
-    Align, Button, CentralPanel, Layout, ScrollArea, TextEdit, TopBottomPanel, Ui, ViewportBuilder,
-    ViewportId, Widget, WidgetText,
+    Align, Button, CentralPanel, Context, Key, Label, Layout, Modifiers, RichText, ScrollArea,
+    TextEdit, TextStyle, TopBottomPanel, Ui, ViewportBuilder, ViewportCommand, ViewportId, Widget,
+    WidgetText,
+};
+use egui_tiles::{
+    Behavior, Container, SimplificationOptions, Tabs, Tile, TileId, Tiles, Tree, UiResponse,
 };
-use egui_tiles::{Behavior, SimplificationOptions, Tabs, Tile, TileId, Tiles, Tree, UiResponse};
 use p256::elliptic_curve::generic_array::GenericArray;
 use p256::elliptic_curve::PrimeField;
 use p256::Scalar;
 
-use crate::net::{Peer, UserMessage};
+use crate::net::{MessageId, NetworkError, Peer, TranscriptHash, UserMessage, MAX_MESSAGE_LENGTH};
+
+use super::peer_panel::peer_color;
+use super::transcript::{self, Entry, Format, Session};
+use super::{Decoy, DemoPane};
+
+static ROW_LINES: f32 = 2.0; // Lines of a sent pair, the height every message row is laid out at
+
+/// Message pair the user asked to send to a peer.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SendRequest {
+    pub addr: SocketAddr,
+    pub m0: UserMessage,
+    pub m1: UserMessage,
+    /// Secret scalar of the transfer, random if not given.
+    pub a: Option<Scalar>,
+}
 
-use super::DemoPane;
+/// Actions that can be performed on the message panel.
+#[derive(Debug)]
+pub enum MessagePanelAction {
+    Send(SendRequest),
+    Choose(SocketAddr, bool),
+    /// The last tile or window of the chat with the peer was closed.
+    Closed(SocketAddr),
+    /// The user asked to ignore the peer from now on.
+    Block(Peer),
+    /// The user took back a message the peer has not answered yet, to edit it.
+    CancelSend(MessageId),
+}
 
 /// Panel to display messages. Allows for sending and receiving messages and taking out and in tiles.
 #[derive(Debug)]
 pub struct MessagePanel {
     messages: HashMap<SocketAddr, Messages>,
     windows: HashMap<TileId, Pane>,
+    /// Window to bring to the front on the next frame.
+    focus: Option<TileId>,
     tree: Tree<Pane>,
     action: Action,
     root: TileId,
+    optimistic: bool,
+    max_length: usize,
+    decoy: Decoy,
+    show_points: bool,
+    debug_export: bool,
 }
 
 impl MessagePanel {
     /// Add a message to the panel.
-    pub fn on_message(&mut self, peer: &Peer, message: String) {
-        let message = Message::Received(message);
+    pub fn on_message(&mut self, peer: &Peer, message: String, verified: bool) {
+        let message = Message::Received(message, verified, SystemTime::now());
         get_entry(&mut self.messages, peer).data.push(message);
     }
 
-    /// Open a tile for the peer.
+    /// Repaint the windows showing the chat with the address. Detached windows are drawn with the
+    /// main one, so they would otherwise stay stale while it is idle.
+    pub fn repaint_windows(&self, ctx: &Context, addr: &SocketAddr) {
+        let windows = self.windows.iter().filter(
+            |(_, pane)| matches!(pane, Pane::Message(pane) if pane.peer.address() == *addr),
+        );
+        for (id, _) in windows {
+            ctx.request_repaint_of(viewport_id(id));
+        }
+    }
+
+    /// Show that a message from the peer arrived but could not be decrypted.
+    pub fn on_decrypt_failed(&mut self, peer: &Peer) {
+        let message = Message::Undecryptable(SystemTime::now());
+        get_entry(&mut self.messages, peer).data.push(message);
+        if let Some(session) = self.session(peer) {
+            session.error = Some("Message could not be decrypted".to_string());
+        }
+    }
+
+    /// Handle a peer starting a transfer. Returns the choice remembered for the peer, otherwise
+    /// the user is asked in the peer's chat.
+    pub fn on_choice_requested(&mut self, peer: &Peer) -> Option<bool> {
+        let messages = get_entry(&mut self.messages, peer);
+        if messages.choice.is_none() {
+            messages.awaiting_choice = true;
+        }
+        messages.choice
+    }
+
+    /// Show the new name of the peer in its conversation and open chats.
+    pub fn on_peer_renamed(&mut self, peer: &Peer) {
+        if let Some(messages) = self.messages.get_mut(&peer.address()) {
+            messages.peer = peer.clone();
+        }
+        for id in self.message_tiles() {
+            if let Some(Tile::Pane(pane)) = self.tree.tiles.get_mut(id) {
+                pane.rename(peer);
+            }
+        }
+        for pane in self.windows.values_mut() {
+            pane.rename(peer);
+        }
+    }
+
+    /// Remember the transcript hash of the last transfer with the peer.
+    pub fn on_transcript(&mut self, peer: &Peer, hash: TranscriptHash) {
+        get_entry(&mut self.messages, peer).transcript_hash = Some(hash);
+    }
+
+    /// Remember the short authentication string of the last handshake with the peer.
+    pub fn on_sas(&mut self, peer: &Peer, sas: String) {
+        get_entry(&mut self.messages, peer).sas = Some(sas);
+    }
+
+    /// Remember the points of the last transfer with the peer, if they are shown.
+    pub fn on_points(&mut self, peer: &Peer, greet: Vec<u8>, response: Vec<u8>) {
+        if let Some(session) = self.session(peer) {
+            session.greet = Some(hex::encode(&greet));
+            session.response = Some(hex::encode(&response));
+        }
+        if self.show_points {
+            get_entry(&mut self.messages, peer).points = Some((greet, response));
+        }
+    }
+
+    /// Start recording a transfer with the peer from its secret scalar, if sessions are exported.
+    /// Either side reports it as the transfer starts, so an earlier session is dropped.
+    pub fn on_scalar(&mut self, peer: &Peer, scalar: &[u8]) {
+        if let Some(session) = self.session(peer) {
+            *session = Session {
+                scalar: Some(hex::encode(scalar)),
+                ..Default::default()
+            };
+        }
+    }
+
+    /// Remember the encrypted pair of the last transfer with the peer, if sessions are exported.
+    pub fn on_ciphertext(&mut self, peer: &Peer, m0: &[u8], m1: &[u8]) {
+        if let Some(session) = self.session(peer) {
+            session.ciphertext = Some([hex::encode(m0), hex::encode(m1)]);
+        }
+    }
+
+    /// Remember the error of the last transfer with the peer, if sessions are exported.
+    pub fn on_error(&mut self, peer: &Peer, error: &NetworkError) {
+        if let Some(session) = self.session(peer) {
+            session.error = Some(error.to_string());
+        }
+    }
+
+    /// Session of the peer to record parameters in, `None` unless sessions are exported.
+    fn session(&mut self, peer: &Peer) -> Option<&mut Session> {
+        if !self.debug_export {
+            return None;
+        }
+        let messages = get_entry(&mut self.messages, peer);
+        Some(messages.session.get_or_insert_with(Session::default))
+    }
+
+    /// Attach the network identifier to the message that is being sent to the address.
+    pub fn on_sent(&mut self, addr: &SocketAddr, id: MessageId) {
+        if let Some(message) = self.pending_message(addr) {
+            message.status = SendStatus::Sent(id);
+        }
+    }
+
+    /// Mark the message that is being sent to the address as failed.
+    pub fn on_send_failed(&mut self, addr: &SocketAddr) {
+        if let Some(message) = self.pending_message(addr) {
+            message.status = SendStatus::Failed;
+        }
+    }
+
+    /// Mark the message with the network identifier as delivered.
+    pub fn on_delivered(&mut self, id: MessageId) {
+        self.set_status(id, SendStatus::Delivered);
+    }
+
+    /// Mark the message with the network identifier as failed.
+    pub fn on_delivery_failed(&mut self, id: MessageId) {
+        self.set_status(id, SendStatus::Failed);
+    }
+
+    fn set_status(&mut self, id: MessageId, status: SendStatus) {
+        let sent = self
+            .messages
+            .values_mut()
+            .flat_map(|messages| &mut messages.data);
+        for message in sent {
+            if let Message::Sent(message) = message {
+                if message.status == SendStatus::Sent(id) {
+                    message.status = status;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn pending_message(&mut self, addr: &SocketAddr) -> Option<&mut SentMessage> {
+        let messages = self.messages.get_mut(addr)?;
+        messages
+            .data
+            .iter_mut()
+            .rev()
+            .find_map(|message| match message {
+                Message::Sent(message) if message.status == SendStatus::Pending => Some(message),
+                _ => None,
+            })
+    }
+
+    /// Limit the number of characters that can be typed into a message.
+    pub fn set_max_message_length(&mut self, max_length: usize) {
+        self.max_length = max_length;
+    }
+
+    /// Set the message sent in place of an empty field.
+    pub fn set_decoy(&mut self, decoy: &Decoy) {
+        if self.decoy != *decoy {
+            self.decoy = decoy.clone();
+        }
+    }
+
+    /// Show the points of the next transfers, or hide them all.
+    pub fn set_show_points(&mut self, show: bool) {
+        if !show && self.show_points {
+            for messages in self.messages.values_mut() {
+                messages.points = None;
+            }
+        }
+        self.show_points = show;
+    }
+
+    /// Record the parameters of the next sessions for exporting, or drop them all.
+    pub fn set_debug_export(&mut self, export: bool) {
+        if !export && self.debug_export {
+            for messages in self.messages.values_mut() {
+                messages.session = None;
+            }
+        }
+        self.debug_export = export;
+    }
+
+    /// Open the demo tile, or switch to it if it is already open.
+    pub fn open_demo(&mut self) {
+        let open = self.tree.tiles.tiles().find_map(|tile| match tile {
+            Tile::Pane(pane @ Pane::Demo(_)) => self.tree.tiles.find_pane(pane),
+            _ => None,
+        });
+        match open {
+            Some(id) => self.activate(id),
+            None => {
+                let id = self.tree.tiles.insert_pane(Pane::Demo(Default::default()));
+                self.tree.move_tile_to_container(id, self.root, 0, true);
+            }
+        }
+    }
+
+    /// Open a tile for the peer, or switch to its chat if it is already open. The messages are
+    /// kept per address, so a second tile would only show the same conversation again.
     pub fn open_tile(&mut self, peer: Peer) {
-        let pane = Pane::Message(MessagePane::new(peer));
-        let id = self.tree.tiles.insert_pane(pane);
-        self.tree.move_tile_to_container(id, self.root, 0, true);
+        let addr = peer.address();
+        let shows =
+            |pane: &Pane| matches!(pane, Pane::Message(pane) if pane.peer.address() == addr);
+        let window = self.windows.iter().find(|(_, pane)| shows(pane));
+        if let Some((id, _)) = window {
+            self.focus = Some(*id);
+            return;
+        }
+        let open = self.tree.tiles.tiles().find_map(|tile| match tile {
+            Tile::Pane(pane) if shows(pane) => self.tree.tiles.find_pane(pane),
+            _ => None,
+        });
+        match open {
+            Some(id) => self.activate(id),
+            None => {
+                let pane = Pane::Message(MessagePane::new(peer));
+                let id = self.tree.tiles.insert_pane(pane);
+                self.tree.move_tile_to_container(id, self.root, 0, true);
+            }
+        }
     }
 
-    /// Show the message panel. Returns data if a message is sent or received.
-    pub fn show(
-        &mut self,
-        ui: &mut Ui,
-    ) -> Option<(SocketAddr, UserMessage, UserMessage, Option<Scalar>)> {
-        let mut behaviour = Behaviour(&mut self.messages, &mut self.action);
+    /// Switch the root tabs to the tile.
+    fn activate(&mut self, id: TileId) {
+        if let Some(Tile::Container(Container::Tabs(tabs))) = self.tree.tiles.get_mut(self.root) {
+            tabs.active = Some(id);
+        }
+    }
+
+    /// Show the message panel. Returns the message to send or the choice made by the user.
+    pub fn show(&mut self, ui: &mut Ui) -> Option<MessagePanelAction> {
+        self.handle_shortcuts(ui);
+        let mut behaviour = Behaviour(
+            &mut self.messages,
+            &mut self.action,
+            &mut self.optimistic,
+            self.max_length,
+            &self.decoy,
+        );
         self.tree.ui(&mut behaviour, ui);
         self.show_windows(ui);
 
@@ -52,10 +317,21 @@ impl MessagePanel {
         std::mem::swap(&mut action, &mut self.action);
 
         match action {
-            Action::Send(addr, m0, m1, a) => Some((addr, m0, m1, a)),
+            Action::Send(request) => {
+                if self.debug_export {
+                    if let Some(messages) = self.messages.get_mut(&request.addr) {
+                        // A new transfer starts, parameters of the previous one no longer apply.
+                        messages.session = Some(Session::default());
+                    }
+                }
+                Some(MessagePanelAction::Send(request))
+            }
+            Action::Choose(addr, c) => Some(MessagePanelAction::Choose(addr, c)),
+            Action::Block(peer) => Some(MessagePanelAction::Block(peer)),
+            Action::CancelSend(id) => Some(MessagePanelAction::CancelSend(id)),
             Action::CloseWindow(id) => {
-                self.windows.remove(&id);
-                None
+                let pane = self.windows.remove(&id);
+                self.on_closed(pane)
             }
             Action::TakeOut(id) => {
                 if let Some(Tile::Pane(pane)) = self.tree.tiles.remove(id) {
@@ -70,18 +346,56 @@ impl MessagePanel {
                 }
                 None
             }
-            Action::Close(id) => {
-                self.tree.tiles.remove(id);
+            Action::TakeOutAll => {
+                self.take_out_all();
+                None
+            }
+            Action::TakeInAll => {
+                self.take_in_all();
                 None
             }
+            Action::Close(id) => match self.tree.tiles.remove(id) {
+                Some(Tile::Pane(pane)) => self.on_closed(Some(pane)),
+                _ => None,
+            },
             Action::None => None,
         }
     }
 
-    /// Close all tiles.
-    pub fn close_all(&mut self) {
+    /// Report a closed chat, unless another tile or window still shows the same peer.
+    fn on_closed(&self, pane: Option<Pane>) -> Option<MessagePanelAction> {
+        let Some(Pane::Message(pane)) = pane else {
+            return None;
+        };
+        let addr = pane.peer.address();
+        let shows =
+            |pane: &Pane| matches!(pane, Pane::Message(pane) if pane.peer.address() == addr);
+        let mut tiles = self.tree.tiles.tiles();
+        let open = self.windows.values().any(shows)
+            || tiles.any(|tile| matches!(tile, Tile::Pane(pane) if shows(pane)));
+        (!open).then_some(MessagePanelAction::Closed(addr))
+    }
+
+    /// Move every chat tile into its own window.
+    pub fn take_out_all(&mut self) {
+        for id in self.message_tiles() {
+            if let Some(Tile::Pane(pane)) = self.tree.tiles.remove(id) {
+                self.windows.insert(id, pane);
+            }
+        }
+    }
+
+    /// Dock every chat window back into the tiles.
+    pub fn take_in_all(&mut self) {
+        for (_, pane) in self.windows.drain() {
+            let id = self.tree.tiles.insert_pane(pane);
+            self.tree.move_tile_to_container(id, self.root, 0, true);
+        }
+    }
+
+    fn message_tiles(&self) -> Vec<TileId> {
         let tiles_iter = self.tree.tiles.tiles();
-        let ids: Vec<TileId> = tiles_iter
+        tiles_iter
             .filter_map(|tile| {
                 if let Tile::Pane(pane) = tile {
                     if let Pane::Message(_) = pane {
@@ -90,29 +404,96 @@ impl MessagePanel {
                 }
                 None
             })
-            .collect();
-        for id in ids {
+            .collect()
+    }
+
+    /// Close all tiles.
+    pub fn close_all(&mut self) {
+        for id in self.message_tiles() {
             self.tree.tiles.remove(id);
         }
         self.windows.clear();
     }
 
+    /// Ctrl+Tab and Ctrl+Shift+Tab cycle the message tabs, Ctrl+W closes the active one.
+    fn handle_shortcuts(&mut self, ui: &Ui) {
+        let (previous, next, close) = ui.input_mut(|input| {
+            // Checked first as the plain shortcut could also match with Shift held.
+            let previous = input.consume_key(Modifiers::CTRL | Modifiers::SHIFT, Key::Tab);
+            let next = input.consume_key(Modifiers::CTRL, Key::Tab);
+            let close = input.consume_key(Modifiers::CTRL, Key::W);
+            (previous, next, close)
+        });
+        if !(previous || next || close) {
+            return;
+        }
+
+        let Some(Tile::Container(Container::Tabs(tabs))) = self.tree.tiles.get(self.root) else {
+            return;
+        };
+        let panes: Vec<TileId> = tabs
+            .children
+            .iter()
+            .copied()
+            .filter(|id| matches!(self.tree.tiles.get(*id), Some(Tile::Pane(Pane::Message(_)))))
+            .collect();
+        let position = tabs
+            .active
+            .and_then(|active| panes.iter().position(|id| *id == active));
+
+        if close {
+            if let Some(position) = position {
+                self.action = Action::Close(panes[position]);
+            }
+            return;
+        }
+
+        if panes.is_empty() {
+            return;
+        }
+
+        let len = panes.len();
+        let target = match (position, next) {
+            (Some(position), true) => (position + 1) % len,
+            (Some(position), false) => (position + len - 1) % len,
+            (None, true) => 0,
+            (None, false) => len - 1,
+        };
+        if let Some(Tile::Container(Container::Tabs(tabs))) = self.tree.tiles.get_mut(self.root) {
+            tabs.active = Some(panes[target]);
+        }
+    }
+
     fn show_windows(&mut self, ui: &mut Ui) {
+        let focus = self.focus.take();
         for (id, pane) in &mut self.windows {
             let title = format!("Oblivious transfer chat: {}", pane.title());
             ui.ctx().show_viewport_immediate(
-                ViewportId::from_hash_of(id),
+                viewport_id(id),
                 ViewportBuilder::default().with_title(title),
                 |ctx, _| {
+                    if focus == Some(*id) {
+                        ctx.send_viewport_cmd(ViewportCommand::Focus);
+                    }
                     TopBottomPanel::top("top_panel").show(ctx, |ui| {
                         ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
                             if ui.button("⤵").clicked() {
                                 self.action = Action::TakeIn(*id);
                             }
+                            if ui.button("⇊").on_hover_text("Dock all").clicked() {
+                                self.action = Action::TakeInAll;
+                            }
                         });
                     });
                     CentralPanel::default().show(ctx, |ui| {
-                        let action = pane.show(ui, *id, &mut self.messages);
+                        let action = pane.show(
+                            ui,
+                            *id,
+                            &mut self.messages,
+                            &mut self.optimistic,
+                            self.max_length,
+                            &self.decoy,
+                        );
                         if let Action::None = self.action {
                             self.action = action;
                         }
@@ -126,27 +507,50 @@ impl MessagePanel {
     }
 }
 
-impl Default for MessagePanel {
-    fn default() -> Self {
+impl MessagePanel {
+    /// Create the panel, starting with the demo tile if `demo` is set.
+    pub fn new(demo: bool) -> Self {
         let mut tiles = Tiles::default();
-        let demo_id = tiles.insert_pane(Pane::Demo(Default::default()));
-        let root = tiles.insert_tab_tile(vec![demo_id]);
+        let children = if demo {
+            vec![tiles.insert_pane(Pane::Demo(Default::default()))]
+        } else {
+            Vec::new()
+        };
+        let root = tiles.insert_tab_tile(children);
         let tree = Tree::new("messages_tree", root, tiles);
         Self {
             messages: Default::default(),
             windows: Default::default(),
+            focus: None,
             tree,
             action: Default::default(),
             root,
+            optimistic: true,
+            max_length: MAX_MESSAGE_LENGTH,
+            decoy: Decoy::default(),
+            show_points: false,
+            debug_export: false,
         }
     }
 }
 
-struct Behaviour<'a>(&'a mut HashMap<SocketAddr, Messages>, &'a mut Action);
+impl Default for MessagePanel {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+struct Behaviour<'a>(
+    &'a mut HashMap<SocketAddr, Messages>,
+    &'a mut Action,
+    &'a mut bool,
+    usize,
+    &'a Decoy,
+);
 
 impl<'a> Behavior<Pane> for Behaviour<'a> {
     fn pane_ui(&mut self, ui: &mut Ui, id: TileId, pane: &mut Pane) -> UiResponse {
-        let action = pane.show(ui, id, self.0);
+        let action = pane.show(ui, id, self.0, self.2, self.3, self.4);
         if let Action::None = self.1 {
             *self.1 = action;
         }
@@ -166,14 +570,26 @@ impl<'a> Behavior<Pane> for Behaviour<'a> {
         _: &mut f32,
     ) {
         if let Some(id) = &tabs.active {
-            if let Some(Tile::Pane(Pane::Message(_))) = tiles.get(*id) {
-                ui.add_space(8.0);
-                if ui.button("✖").clicked() {
-                    *self.1 = Action::Close(*id);
+            match tiles.get(*id) {
+                Some(Tile::Pane(Pane::Message(_))) => {
+                    ui.add_space(8.0);
+                    if ui.button("✖").clicked() {
+                        *self.1 = Action::Close(*id);
+                    }
+                    if ui.button("⤴").clicked() {
+                        *self.1 = Action::TakeOut(*id);
+                    }
+                    if ui.button("⇈").on_hover_text("Take out all").clicked() {
+                        *self.1 = Action::TakeOutAll;
+                    }
                 }
-                if ui.button("⤴").clicked() {
-                    *self.1 = Action::TakeOut(*id);
+                Some(Tile::Pane(Pane::Demo(_))) => {
+                    ui.add_space(8.0);
+                    if ui.button("✖").on_hover_text("Close the demo").clicked() {
+                        *self.1 = Action::Close(*id);
+                    }
                 }
+                _ => {}
             }
         }
     }
@@ -181,6 +597,8 @@ impl<'a> Behavior<Pane> for Behaviour<'a> {
     fn simplification_options(&self) -> SimplificationOptions {
         SimplificationOptions {
             all_panes_must_have_tabs: true,
+            // Without the demo the root can run out of tabs, new chats are still opened in it.
+            prune_empty_tabs: false,
             ..Default::default()
         }
     }
@@ -190,6 +608,13 @@ impl<'a> Behavior<Pane> for Behaviour<'a> {
 pub struct Messages {
     data: Vec<Message>,
     peer: Peer,
+    choice: Option<bool>,
+    awaiting_choice: bool,
+    remember_choice: bool,
+    transcript_hash: Option<TranscriptHash>,
+    points: Option<(Vec<u8>, Vec<u8>)>,
+    sas: Option<String>,
+    session: Option<Session>,
 }
 
 impl Messages {
@@ -197,14 +622,70 @@ impl Messages {
         Self {
             data: Default::default(),
             peer,
+            choice: None,
+            awaiting_choice: false,
+            remember_choice: false,
+            transcript_hash: None,
+            points: None,
+            sas: None,
+            session: None,
         }
     }
+
+    /// Entries of the conversation for exporting.
+    fn transcript(&self) -> Vec<Entry> {
+        let entry = |time, sender, text, status| Entry {
+            time,
+            sender,
+            text,
+            status,
+        };
+        self.data
+            .iter()
+            .map(|message| match message {
+                Message::Received(text, verified, time) => {
+                    let status = if *verified { "verified" } else { "received" };
+                    entry(*time, self.peer.to_string(), vec![text.as_str()], status)
+                }
+                Message::Undecryptable(time) => {
+                    entry(*time, self.peer.to_string(), vec![], "undecryptable")
+                }
+                Message::Sent(message) => {
+                    let status = match message.status {
+                        SendStatus::Pending | SendStatus::Sent(_) => "pending",
+                        SendStatus::Delivered => "delivered",
+                        SendStatus::Failed => "failed",
+                    };
+                    let text = vec![&*message.m0, &*message.m1];
+                    entry(message.time, "Me".to_string(), text, status)
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug)]
 enum Message {
-    Received(String),
-    Sent(String, String),
+    Received(String, bool, SystemTime),
+    Undecryptable(SystemTime),
+    Sent(SentMessage),
+}
+
+#[derive(Debug)]
+struct SentMessage {
+    m0: UserMessage,
+    m1: UserMessage,
+    a: Option<Scalar>,
+    status: SendStatus,
+    time: SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SendStatus {
+    Pending,
+    Sent(MessageId),
+    Delivered,
+    Failed,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -214,9 +695,20 @@ enum Pane {
 }
 
 impl Pane {
-    fn show(&mut self, ui: &mut Ui, id: TileId, d: &mut HashMap<SocketAddr, Messages>) -> Action {
+    fn show(
+        &mut self,
+        ui: &mut Ui,
+        id: TileId,
+        d: &mut HashMap<SocketAddr, Messages>,
+        optimistic: &mut bool,
+        max_length: usize,
+        decoy: &Decoy,
+    ) -> Action {
         match self {
-            Pane::Message(pane) => pane.show(ui, id, get_entry(d, &pane.peer)),
+            Pane::Message(pane) => {
+                let messages = get_entry(d, &pane.peer);
+                pane.show(ui, id, messages, optimistic, max_length, decoy)
+            }
             Pane::Demo(pane) => {
                 pane.draw(ui);
                 Action::None
@@ -224,6 +716,14 @@ impl Pane {
         }
     }
 
+    fn rename(&mut self, peer: &Peer) {
+        if let Pane::Message(pane) = self {
+            if pane.peer.address() == peer.address() {
+                pane.peer = peer.clone();
+            }
+        }
+    }
+
     fn title(&self) -> String {
         match self {
             Pane::Message(pane) => pane.peer.to_string(),
@@ -234,10 +734,15 @@ impl Pane {
 
 #[derive(Debug, Default, Eq, PartialEq)]
 enum Action {
-    Send(SocketAddr, UserMessage, UserMessage, Option<Scalar>),
+    Send(SendRequest),
+    Choose(SocketAddr, bool),
+    Block(Peer),
+    CancelSend(MessageId),
     CloseWindow(TileId),
     TakeOut(TileId),
     TakeIn(TileId),
+    TakeOutAll,
+    TakeInAll,
     Close(TileId),
     #[default]
     None,
@@ -246,10 +751,12 @@ enum Action {
 #[derive(Debug, Eq, PartialEq)]
 struct MessagePane {
     peer: Peer,
-    m0: UserMessage,
-    m1: UserMessage,
+    m0: String,
+    m1: String,
     custom_a: bool,
     a: String,
+    export_status: Option<String>,
+    send_error: Option<String>,
 }
 
 impl MessagePane {
@@ -260,28 +767,163 @@ impl MessagePane {
             m1: Default::default(),
             custom_a: Default::default(),
             a: Default::default(),
+            export_status: None,
+            send_error: None,
         }
     }
 }
 
 impl MessagePane {
-    fn show(&mut self, ui: &mut Ui, id: TileId, messages: &mut Messages) -> Action {
+    fn show(
+        &mut self,
+        ui: &mut Ui,
+        id: TileId,
+        messages: &mut Messages,
+        optimistic: &mut bool,
+        max_length: usize,
+        decoy: &Decoy,
+    ) -> Action {
         let peer = &messages.peer;
         let mut result = Default::default();
 
+        let mut send = false;
+        let mut export = None;
         let panel_id = format!("bottom_panel_{peer}_{id:?}");
         TopBottomPanel::bottom(panel_id).show_inside(ui, |ui| {
             ui.with_layout(Layout::right_to_left(Align::BOTTOM), |ui| {
                 let button = Button::new("Send");
-                if ui.add_enabled(self.is_valid(), button).clicked() {
-                    let mut new_m0 = UserMessage::default();
-                    let mut new_m1 = UserMessage::default();
+                let hint = if self.is_empty() {
+                    "Type a message to send"
+                } else {
+                    "A message is too long or the scalar is not hex"
+                };
+                send = ui
+                    .add_enabled(self.is_valid(max_length), button)
+                    .on_disabled_hover_text(hint)
+                    .clicked();
+                ui.vertical(|ui| {
+                    let hint = match decoy {
+                        Decoy::Off => "",
+                        _ => "Leave empty to send a decoy",
+                    };
+                    let m0 = TextEdit::singleline(&mut self.m0)
+                        .hint_text(hint)
+                        .desired_width(ui.available_width())
+                        .ui(ui);
+                    let m1 = TextEdit::singleline(&mut self.m1)
+                        .hint_text(hint)
+                        .desired_width(ui.available_width())
+                        .ui(ui);
+
+                    // Enter on m0 moves to m1, Enter on m1 sends and starts the next pair.
+                    if m0.lost_focus() && enter_pressed(ui) {
+                        m1.request_focus();
+                    }
+                    if m1.lost_focus() && enter_pressed(ui) && self.is_valid(max_length) {
+                        send = true;
+                        m0.request_focus();
+                    }
 
-                    std::mem::swap(&mut self.m0, &mut new_m0);
-                    std::mem::swap(&mut self.m1, &mut new_m1);
+                    // Long input is kept rather than truncated, sending is disabled until it fits.
+                    ui.horizontal(|ui| {
+                        for text in [&self.m0, &self.m1] {
+                            let count = text.chars().count();
+                            let counter = RichText::new(format!("{count}/{max_length}"));
+                            if fits(text, max_length) {
+                                ui.label(counter.weak());
+                            } else {
+                                ui.label(counter.color(ui.visuals().error_fg_color))
+                                    .on_hover_text("Too long to send");
+                            }
+                        }
+                        if let Some(error) = &self.send_error {
+                            ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+                        }
+                    });
 
-                    let message = Message::Sent(new_m0.to_string(), new_m1.to_string());
-                    messages.data.push(message);
+                    ui.horizontal(|ui| {
+                        ui.checkbox(optimistic, "Show before delivery")
+                            .on_hover_text("Show sent messages before the transfer completes");
+                        ui.checkbox(&mut self.custom_a, "Custom scalar:");
+                        let edit =
+                            TextEdit::singleline(&mut self.a).desired_width(ui.available_width());
+                        ui.add_enabled(self.custom_a, edit);
+                    });
+
+                    ui.horizontal(|ui| {
+                        let block = ui
+                            .button("🚫")
+                            .on_hover_text("Block the peer, ignoring its messages from now on");
+                        if block.clicked() {
+                            result = Action::Block(peer.clone());
+                        }
+                        ui.menu_button("Export", |ui| {
+                            if ui.button("Text").clicked() {
+                                export = Some(Format::Text);
+                                ui.close_menu();
+                            }
+                            if ui.button("JSON").clicked() {
+                                export = Some(Format::Json);
+                                ui.close_menu();
+                            }
+                        });
+                        if let Some(status) = &self.export_status {
+                            ui.label(status.as_str());
+                        }
+                        if let Some(sas) = &messages.sas {
+                            ui.label(format!("SAS {sas}")).on_hover_text(
+                                "Read it aloud with the peer, a different one means a man in the \
+                                 middle",
+                            );
+                        }
+                        if let Some(hash) = &messages.transcript_hash {
+                            let hash = hex::encode(hash);
+                            ui.label(format!("Session {}…", &hash[..16])).on_hover_text(
+                                "Transcript hash of the last transfer, compare it with the peer \
+                                 out-of-band to detect a man in the middle",
+                            );
+                            if ui.button("📋").on_hover_text(&hash).clicked() {
+                                ui.output_mut(|output| output.copied_text = hash);
+                            }
+                        }
+                        if let Some((greet, response)) = &messages.points {
+                            for (name, point) in [("A", greet), ("B", response)] {
+                                let point = hex::encode(point);
+                                ui.label(format!("{name} {}…", &point[..16])).on_hover_text(
+                                    "Compressed point exchanged in the last transfer",
+                                );
+                                if ui.button("📋").on_hover_text(&point).clicked() {
+                                    ui.output_mut(|output| output.copied_text = point);
+                                }
+                            }
+                        }
+                        if let Some(session) = &messages.session {
+                            let copy = ui.button("🐞").on_hover_text(
+                                "Copy the parameters of the last session as JSON, for a bug report",
+                            );
+                            if copy.clicked() {
+                                match transcript::session_to_json(peer, session) {
+                                    Ok(json) => ui.output_mut(|output| output.copied_text = json),
+                                    Err(error) => {
+                                        self.export_status = Some(format!("Export failed: {error}"))
+                                    }
+                                }
+                            }
+                        }
+                    });
+                });
+            });
+        });
+
+        if send {
+            let (mut m0, mut m1) = (self.m0.clone(), self.m1.clone());
+            fill_decoy(&mut m0, &mut m1, decoy);
+
+            match (UserMessage::try_from(m0), UserMessage::try_from(m1)) {
+                (Ok(new_m0), Ok(new_m1)) => {
+                    self.m0.clear();
+                    self.m1.clear();
+                    self.send_error = None;
 
                     let a = if self.custom_a {
                         let mut buffer = [0; 32];
@@ -293,55 +935,175 @@ impl MessagePane {
                         None
                     };
 
-                    result = Action::Send(peer.address(), new_m0, new_m1, a);
-                }
-                ui.vertical(|ui| {
-                    TextEdit::singleline(&mut self.m0)
-                        .desired_width(ui.available_width())
-                        .ui(ui);
-                    TextEdit::singleline(&mut self.m1)
-                        .desired_width(ui.available_width())
-                        .ui(ui);
-                    ui.horizontal(|ui| {
-                        ui.checkbox(&mut self.custom_a, "Custom scalar:");
-                        let edit =
-                            TextEdit::singleline(&mut self.a).desired_width(ui.available_width());
-                        ui.add_enabled(self.custom_a, edit);
+                    messages.data.push(Message::Sent(SentMessage {
+                        m0: new_m0.clone(),
+                        m1: new_m1.clone(),
+                        a,
+                        status: SendStatus::Pending,
+                        time: SystemTime::now(),
+                    }));
+
+                    result = Action::Send(SendRequest {
+                        addr: peer.address(),
+                        m0: new_m0,
+                        m1: new_m1,
+                        a,
                     });
-                });
+                }
+                (Err(error), _) | (_, Err(error)) => self.send_error = Some(error.to_string()),
+            }
+        }
+
+        if let Some(format) = export {
+            self.export_status = match transcript::export(peer, &messages.transcript(), format) {
+                Ok(Some(path)) => Some(format!("Exported to {}", path.display())),
+                Ok(None) => None,
+                Err(error) => Some(format!("Export failed: {error}")),
+            };
+        }
+
+        let mut choice = None;
+        if messages.awaiting_choice {
+            ui.horizontal(|ui| {
+                ui.label(format!("{peer} is sending a message pair. Receive:"));
+                if ui.button("First").clicked() {
+                    choice = Some(false);
+                }
+                if ui.button("Second").clicked() {
+                    choice = Some(true);
+                }
+                ui.checkbox(&mut messages.remember_choice, "Always for this peer");
             });
-        });
+        } else if let Some(c) = messages.choice {
+            ui.horizontal(|ui| {
+                let message = if c { "second" } else { "first" };
+                ui.label(format!("Receiving the {message} message of every pair"));
+                if ui.button("Ask each time").clicked() {
+                    messages.choice = None;
+                }
+            });
+        }
+
+        if let Some(c) = choice {
+            messages.awaiting_choice = false;
+            if messages.remember_choice {
+                messages.choice = Some(c);
+            }
+            result = Action::Choose(peer.address(), c);
+        }
 
-        ScrollArea::vertical().show(ui, |ui| {
-            ui.vertical(|ui| {
-                for message in &messages.data {
+        // Only the visible rows are laid out, so long histories do not slow down every frame.
+        // That needs rows of one height, so every text is truncated to a single line and shown
+        // in full on hover.
+        let spacing = ui.spacing().item_spacing.y;
+        let row_height = ui.text_style_height(&TextStyle::Body) * ROW_LINES + spacing;
+        let total = messages.data.len();
+        let mut edit = None;
+        ScrollArea::vertical().stick_to_bottom(true).show_rows(
+            ui,
+            row_height,
+            total,
+            |ui, rows| {
+                let first = rows.start;
+                for (index, message) in messages.data[rows].iter_mut().enumerate() {
                     match message {
-                        Message::Received(message) => {
+                        Message::Received(message, verified, _) => {
                             ui.horizontal(|ui| {
-                                ui.label(format!("{peer}:"));
-                                ui.label(message);
+                                ui.set_min_height(row_height);
+                                if cfg!(feature = "signing") {
+                                    if *verified {
+                                        ui.label("✔").on_hover_text("Verified sender");
+                                    } else {
+                                        ui.label("⚠").on_hover_text("Unverified sender");
+                                    }
+                                }
+                                let color = peer_color(peer, ui.visuals().dark_mode);
+                                ui.label(RichText::new(format!("{peer}:")).color(color));
+                                ui.add(Label::new(message.as_str()).truncate(true));
                                 ui.add_space(ui.available_width());
                             });
                         }
-                        Message::Sent(m0, m1) => {
+                        Message::Undecryptable(_) => {
                             ui.horizontal(|ui| {
+                                ui.set_min_height(row_height);
+                                let text = format!("⚠ Couldn't decrypt message from {peer}");
+                                ui.colored_label(ui.visuals().warn_fg_color, text)
+                                    .on_hover_text("A message was sent to you but was not valid");
+                                ui.add_space(ui.available_width());
+                            });
+                        }
+                        Message::Sent(message) => {
+                            ui.horizontal(|ui| {
+                                ui.set_min_height(row_height);
+                                if message.status == SendStatus::Failed {
+                                    ui.label("⚠").on_hover_text("Failed to send");
+                                    if ui.button("↻").on_hover_text("Retry").clicked() {
+                                        message.status = SendStatus::Pending;
+                                        result = Action::Send(SendRequest {
+                                            addr: peer.address(),
+                                            m0: message.m0.clone(),
+                                            m1: message.m1.clone(),
+                                            a: message.a,
+                                        });
+                                    }
+                                }
+                                if let SendStatus::Sent(_) = message.status {
+                                    let button = ui.button("✏").on_hover_text(
+                                        "Take the message back to edit it, the peer has not \
+                                         answered yet",
+                                    );
+                                    if button.clicked() {
+                                        edit = Some(first + index);
+                                    }
+                                }
                                 ui.label("Me:");
-                                ui.vertical(|ui| {
-                                    ui.label(m0);
-                                    ui.label(m1);
-                                });
+                                let settled = matches!(
+                                    message.status,
+                                    SendStatus::Delivered | SendStatus::Failed
+                                );
+                                if *optimistic || settled {
+                                    ui.vertical(|ui| {
+                                        ui.add(Label::new(&*message.m0).truncate(true));
+                                        ui.add(Label::new(&*message.m1).truncate(true));
+                                    });
+                                } else {
+                                    ui.spinner();
+                                    ui.label("Pending delivery");
+                                }
                                 ui.add_space(ui.available_width());
                             });
                         }
                     }
                 }
-            });
-        });
+            },
+        );
+
+        if let Some(index) = edit {
+            if let Message::Sent(message) = messages.data.remove(index) {
+                self.m0 = String::from(&*message.m0);
+                self.m1 = String::from(&*message.m1);
+                if let Some(a) = message.a {
+                    self.custom_a = true;
+                    self.a = hex::encode(a.to_repr());
+                }
+                if let SendStatus::Sent(id) = message.status {
+                    result = Action::CancelSend(id);
+                }
+            }
+        }
 
         result
     }
 
-    fn is_valid(&self) -> bool {
+    /// Check if both messages are empty, there is nothing worth transferring then.
+    fn is_empty(&self) -> bool {
+        self.m0.is_empty() && self.m1.is_empty()
+    }
+
+    fn is_valid(&self, max_length: usize) -> bool {
+        if self.is_empty() || !fits(&self.m0, max_length) || !fits(&self.m1, max_length) {
+            return false;
+        }
         if self.custom_a {
             hex::decode(&self.a).is_ok()
         } else {
@@ -350,6 +1112,33 @@ impl MessagePane {
     }
 }
 
+/// Check if the text is within the configured length and the protocol limit.
+fn fits(text: &str, max_length: usize) -> bool {
+    text.chars().count() <= max_length && text.len() <= MAX_MESSAGE_LENGTH
+}
+
+/// Replace the empty message of the pair with a decoy. Two real or two empty messages are kept.
+fn fill_decoy(m0: &mut String, m1: &mut String, decoy: &Decoy) {
+    let (empty, real) = match (m0.is_empty(), m1.is_empty()) {
+        (true, false) => (m0, &*m1),
+        (false, true) => (m1, &*m0),
+        _ => return,
+    };
+    if let Some(text) = decoy.fill(real) {
+        *empty = text;
+    }
+}
+
+/// Viewport of the window the tile was taken out into.
+fn viewport_id(id: &TileId) -> ViewportId {
+    ViewportId::from_hash_of(id)
+}
+
+/// Check if Enter was pressed without Shift to submit a field.
+fn enter_pressed(ui: &Ui) -> bool {
+    ui.input(|input| input.key_pressed(Key::Enter) && !input.modifiers.shift)
+}
+
 fn get_entry<'a>(messages: &'a mut HashMap<SocketAddr, Messages>, peer: &Peer) -> &'a mut Messages {
     match messages.entry(peer.address()) {
         Entry::Occupied(entry) => entry.into_mut(),
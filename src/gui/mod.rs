@@ -2,20 +2,23 @@ use app::*;
 use demo_pane::*;
 pub use message_panel::*;
 pub use peer_panel::*;
+pub use settings::*;
 pub use top_panel::*;
 
 mod app;
 mod demo_pane;
 mod message_panel;
 mod peer_panel;
+mod settings;
 mod top_panel;
+mod transcript;
 
 /// Run app.
 pub fn run() -> Result<(), Box<dyn std::error::Error>> {
     eframe::run_native(
         "Oblivious Transfer Protocol",
         Default::default(),
-        Box::new(|_| Box::<App>::default()),
+        Box::new(|cc| Box::new(App::new(cc))),
     )?;
     Ok(())
 }
@@ -1,81 +1,277 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use eframe::egui::{Button, ScrollArea, TextEdit, Ui, Vec2, Widget};
+use eframe::egui::ecolor::Hsva;
+use eframe::egui::{
+    Align, Button, Color32, Key, Layout, RichText, ScrollArea, TextEdit, Ui, Widget,
+};
 
-use crate::net::Peer;
+use crate::net::{Peer, ECHO_ADDRESS, MAX_MESSAGE_LENGTH};
+
+static STALE_AFTER: Duration = Duration::from_secs(60);
 
 /// Panel that shows the list of peers.
 #[derive(Debug, Default)]
-pub struct PeerPanel(BTreeMap<SocketAddr, Peer>, String);
+pub struct PeerPanel {
+    peers: BTreeMap<SocketAddr, Peer>,
+    new_peer: String,
+    /// Host name being looked up, disabling the add button meanwhile.
+    resolving: Option<String>,
+    focus_search: bool,
+    rtt: HashMap<SocketAddr, Duration>,
+    /// Start of the running refresh, peers not heard from since are grayed out.
+    refreshing_since: Option<Instant>,
+    announcement: String,
+}
 
 /// Actions that can be performed on the peer panel.
 pub enum PeerPanelAction<'a> {
     PeerClicked(&'a Peer),
+    PeerAdded(SocketAddr),
+    NewChat(SocketAddr),
+    PeerResolve(String),
+    Ping(SocketAddr),
+    Block(&'a Peer),
+    Announce(String),
     RefreshPeers,
     None,
 }
 
 impl PeerPanel {
     /// Draw the peer panel. Returns the peer that was clicked.
-    pub fn draw(&mut self, ui: &mut Ui) -> PeerPanelAction {
+    /// Peers not heard from recently according to `last_seen`, or not yet since a refresh, are
+    /// grayed out. The last measured round-trip time is shown next to each peer.
+    pub fn draw(
+        &mut self,
+        ui: &mut Ui,
+        last_seen: impl Fn(&SocketAddr) -> Option<Instant>,
+    ) -> PeerPanelAction {
         let mut action = PeerPanelAction::None;
 
         ui.vertical(|ui| {
             ui.horizontal(|ui| {
-                if ui.button("↻").on_hover_text("Refresh peers").clicked() {
+                let refresh = ui.add_enabled(self.refreshing_since.is_none(), Button::new("↻"));
+                if refresh.on_hover_text("Refresh peers").clicked() {
                     action = PeerPanelAction::RefreshPeers;
-                    self.clear_peers();
+                    self.refreshing_since = Some(Instant::now());
                 }
                 ui.label("Peers");
+                if self.refreshing_since.is_some() {
+                    ui.spinner();
+                }
             });
 
             ui.horizontal(|ui| {
-                let enabled = SocketAddr::from_str(&self.1).is_ok();
+                let address = SocketAddr::from_str(&self.new_peer);
+                let enabled =
+                    self.resolving.is_none() && (address.is_ok() || is_host_name(&self.new_peer));
                 if ui.add_enabled(enabled, Button::new("Add")).clicked() {
-                    self.add_peer(Peer::new(SocketAddr::from_str(&self.1).unwrap()));
-                    self.1.clear();
+                    let host = std::mem::take(&mut self.new_peer);
+                    action = match address {
+                        Ok(address) => {
+                            self.add_peer(Peer::new(address));
+                            PeerPanelAction::PeerAdded(address)
+                        }
+                        Err(_) => {
+                            self.resolving = Some(host.clone());
+                            PeerPanelAction::PeerResolve(host)
+                        }
+                    };
                 }
-                TextEdit::singleline(&mut self.1)
-                    .hint_text("Peer address")
+                let chat = ui
+                    .add_enabled(
+                        self.resolving.is_none() && address.is_ok(),
+                        Button::new("💬"),
+                    )
+                    .on_hover_text("Open a chat to the address without waiting for discovery");
+                if let (true, Ok(address)) = (chat.clicked(), address) {
+                    self.new_peer.clear();
+                    self.add_peer(Peer::new(address));
+                    action = PeerPanelAction::NewChat(address);
+                }
+                let edit = TextEdit::singleline(&mut self.new_peer)
+                    .hint_text("Peer address or host:port")
                     .desired_width(ui.available_width())
                     .ui(ui);
+                if std::mem::take(&mut self.focus_search) {
+                    edit.request_focus();
+                }
+            });
+
+            if let Some(host) = &self.resolving {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(format!("Resolving {host}"));
+                });
+            }
+
+            ui.horizontal(|ui| {
+                let valid =
+                    !self.announcement.is_empty() && self.announcement.len() <= MAX_MESSAGE_LENGTH;
+                let announce = ui
+                    .add_enabled(valid, Button::new("📢"))
+                    .on_hover_text("Broadcast the text to every peer, unencrypted");
+                let edit = TextEdit::singleline(&mut self.announcement)
+                    .hint_text("Announcement")
+                    .desired_width(ui.available_width())
+                    .ui(ui);
+                let entered = edit.lost_focus() && ui.input(|input| input.key_pressed(Key::Enter));
+                if valid && (announce.clicked() || entered) {
+                    action = PeerPanelAction::Announce(std::mem::take(&mut self.announcement));
+                }
             });
 
             ui.separator();
 
             ScrollArea::vertical().show(ui, |ui| {
-                let size = Vec2::new(ui.available_width(), 0.0);
-                for peer in self.0.values() {
-                    let button = Button::new(peer.to_string()).frame(false).min_size(size);
-                    if button.ui(ui).clicked() {
-                        action = PeerPanelAction::PeerClicked(peer);
+                for peer in self.peers.values() {
+                    let seen = last_seen(&peer.address());
+                    let elapsed = seen.map(|seen| seen.elapsed());
+                    let unanswered = match (self.refreshing_since, seen) {
+                        (Some(refresh), Some(seen)) => seen < refresh,
+                        (refresh, None) => refresh.is_some(),
+                        (None, Some(_)) => false,
+                    };
+                    let echo = peer.address() == ECHO_ADDRESS;
+                    let mut text = RichText::new(if echo {
+                        format!("🔁 {peer}")
+                    } else {
+                        peer.to_string()
+                    });
+                    if unanswered || elapsed.map_or(true, |elapsed| elapsed > STALE_AFTER) {
+                        text = text.weak();
+                    } else {
+                        text = text.color(peer_color(peer, ui.visuals().dark_mode));
                     }
+
+                    ui.horizontal(|ui| {
+                        let response = Button::new(text).frame(false).ui(ui);
+                        let response = match elapsed {
+                            _ if echo => response.on_hover_text(
+                                "Built-in test peer, sends back the message it receives",
+                            ),
+                            Some(elapsed) => response.on_hover_text(format_last_seen(elapsed)),
+                            None => response.on_hover_text("Never seen"),
+                        };
+                        if response.clicked() {
+                            action = PeerPanelAction::PeerClicked(peer);
+                        }
+                        ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                            let ping = ui
+                                .small_button("📶")
+                                .on_hover_text("Measure round-trip time");
+                            if ping.clicked() {
+                                action = PeerPanelAction::Ping(peer.address());
+                            }
+                            let block = !echo
+                                && ui
+                                    .small_button("🚫")
+                                    .on_hover_text("Block, ignoring its messages from now on")
+                                    .clicked();
+                            if block {
+                                action = PeerPanelAction::Block(peer);
+                            }
+                            if let Some(rtt) = self.rtt.get(&peer.address()) {
+                                ui.weak(format!("{} ms", rtt.as_millis()));
+                            }
+                        });
+                    });
                 }
             });
         });
 
+        ui.ctx().request_repaint_after(Duration::from_secs(1));
+
         action
     }
 
+    /// Focus the field for adding peers by address on the next draw.
+    pub fn focus_add_field(&mut self) {
+        self.focus_search = true;
+    }
+
     /// Add a peer to the panel.
     pub fn add_peer(&mut self, peer: Peer) {
-        self.0.insert(peer.address(), peer);
+        self.peers.insert(peer.address(), peer);
     }
 
     /// Remove a peer from the panel.
     pub fn remove_peer(&mut self, address: &SocketAddr) {
-        self.0.remove(address);
+        self.peers.remove(address);
+        self.rtt.remove(address);
     }
 
-    /// Clear all peers from the panel.
+    /// Clear all peers from the panel, forgetting any pending resolution.
     pub fn clear_peers(&mut self) {
-        self.0.clear();
+        self.peers.clear();
+        self.resolving = None;
+        self.rtt.clear();
+        self.refreshing_since = None;
+    }
+
+    /// End the refresh, keeping only the peers still `known` to the network host.
+    pub fn on_refreshed(&mut self, known: impl Fn(&SocketAddr) -> bool) {
+        self.peers.retain(|address, _| known(address));
+        self.rtt.retain(|address, _| known(address));
+        self.refreshing_since = None;
+    }
+
+    /// Show the round-trip time measured to the peer.
+    pub fn on_pong(&mut self, address: SocketAddr, rtt: Duration) {
+        self.rtt.insert(address, rtt);
+    }
+
+    /// Add the peer a host name resolved to.
+    pub fn on_resolved(&mut self, host: &str, address: SocketAddr) {
+        if self.resolving.as_deref() == Some(host) {
+            self.resolving = None;
+        }
+        self.add_peer(Peer::new(address));
+    }
+
+    /// Stop showing the host name as being resolved.
+    pub fn on_resolve_failed(&mut self, host: &str) {
+        if self.resolving.as_deref() == Some(host) {
+            self.resolving = None;
+        }
     }
 
     /// Get peer by socket address.
     pub fn get_peer(&self, addr: &SocketAddr) -> Option<Peer> {
-        self.0.get(addr).cloned()
+        self.peers.get(addr).cloned()
+    }
+}
+
+/// Color of the peer, derived from its address so it stays the same across restarts.
+pub(super) fn peer_color(peer: &Peer, dark_mode: bool) -> Color32 {
+    // FNV-1a, unlike the std hasher it is guaranteed not to change between Rust versions.
+    let hash = peer
+        .address()
+        .to_string()
+        .bytes()
+        .fold(0x811c9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x01000193)
+        });
+    let hue = hash as f32 / u32::MAX as f32;
+    let value = if dark_mode { 0.85 } else { 0.55 };
+    Hsva::new(hue, 0.6, value, 1.0).into()
+}
+
+/// Check if the text has the `host:port` form accepted for name resolution.
+fn is_host_name(text: &str) -> bool {
+    match text.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+fn format_last_seen(elapsed: Duration) -> String {
+    match elapsed.as_secs() {
+        0..=4 => "Seen just now".to_string(),
+        seconds @ 5..=59 => format!("Seen {seconds}s ago"),
+        seconds @ 60..=3599 => format!("Seen {}m ago", seconds / 60),
+        seconds => format!("Seen {}h ago", seconds / 3600),
     }
 }
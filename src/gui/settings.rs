@@ -0,0 +1,343 @@
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use eframe::egui::{self, Context, DragValue, Ui, Visuals};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::net::{
+    Discovery, NetworkOptions, Peer, Username, MAX_MESSAGE_LENGTH, MAX_USERNAME_LENGTH,
+};
+
+static DEFAULT_PORT: u16 = 12345;
+static DEFAULT_HEARTBEAT: u64 = 30; // Seconds, below the time after which peers are shown as stale
+static DEFAULT_DECOY: &str = "nothing here";
+
+/// Color theme of the GUI.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+impl Theme {
+    fn visuals(self) -> Visuals {
+        match self {
+            Theme::Dark => Visuals::dark(),
+            Theme::Light => Visuals::light(),
+        }
+    }
+}
+
+/// Message sent in place of an empty field, so a single message still runs a 1-of-2 transfer.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Decoy {
+    /// Send the empty field as it is.
+    Off,
+    /// Send a fixed text.
+    Text(String),
+    /// Send random letters and digits as long as the real message.
+    Random,
+}
+
+impl Decoy {
+    /// Get the decoy for the real message, `None` if disabled.
+    pub fn fill(&self, real: &str) -> Option<String> {
+        match self {
+            Decoy::Off => None,
+            Decoy::Text(text) => Some(text.clone()),
+            Decoy::Random => {
+                let length = real.chars().count().max(1);
+                let chars = thread_rng().sample_iter(&Alphanumeric).take(length);
+                Some(chars.map(char::from).collect())
+            }
+        }
+    }
+}
+
+impl Default for Decoy {
+    fn default() -> Self {
+        Decoy::Text(DEFAULT_DECOY.to_string())
+    }
+}
+
+/// Addresses and usernames whose messages are dropped by the network task.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Blocklist {
+    pub addresses: BTreeSet<IpAddr>,
+    pub names: BTreeSet<String>,
+}
+
+impl Blocklist {
+    /// Block the address of the peer on any port, and its name if it has one.
+    pub fn block(&mut self, peer: &Peer) {
+        self.addresses.insert(peer.address().ip());
+        if let Some(name) = peer.name() {
+            self.names.insert(name.to_string());
+        }
+    }
+
+    /// Names to block in the network task, skipping any that are not valid usernames.
+    pub fn usernames(&self) -> BTreeSet<Username> {
+        let names = self.names.iter().cloned();
+        names.filter_map(|name| Username::new(name).ok()).collect()
+    }
+
+    /// Show the blocked entries, each with a button to unblock it.
+    fn show(&mut self, ui: &mut Ui) {
+        if self.addresses.is_empty() && self.names.is_empty() {
+            ui.weak("Nobody");
+            return;
+        }
+        ui.vertical(|ui| {
+            let mut unblocked = None;
+            for address in &self.addresses {
+                ui.horizontal(|ui| {
+                    ui.label(address.to_string());
+                    if ui.small_button("✖").on_hover_text("Unblock").clicked() {
+                        unblocked = Some(*address);
+                    }
+                });
+            }
+            if let Some(address) = unblocked {
+                self.addresses.remove(&address);
+            }
+            let mut unblocked = None;
+            for name in &self.names {
+                ui.horizontal(|ui| {
+                    ui.label(name.as_str());
+                    if ui.small_button("✖").on_hover_text("Unblock").clicked() {
+                        unblocked = Some(name.clone());
+                    }
+                });
+            }
+            if let Some(name) = unblocked {
+                self.names.remove(&name);
+            }
+        });
+    }
+}
+
+/// User configurable settings, persisted between runs.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    pub port: u16,
+    pub port_fallback: bool,
+    pub bind_address: String,
+    pub username: String,
+    pub theme: Theme,
+    pub max_message_length: usize,
+    pub heartbeat_interval: u64,
+    pub max_sessions: usize,
+    pub hide_name: bool,
+    pub discovery: Discovery,
+    pub notifications: bool,
+    pub decoy: Decoy,
+    pub show_demo: bool,
+    pub self_test: bool,
+    pub show_points: bool,
+    pub debug_export: bool,
+    pub blocklist: Blocklist,
+}
+
+impl Settings {
+    /// Key of the settings in the eframe storage.
+    pub const KEY: &'static str = "settings";
+
+    /// Address to bind the socket to. All interfaces if the address is empty or invalid.
+    pub fn bind(&self) -> SocketAddr {
+        let ip = self.bind_address.trim().parse();
+        SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), self.port)
+    }
+
+    /// Options of the network host.
+    pub fn network_options(&self) -> NetworkOptions {
+        NetworkOptions {
+            hide_name: self.hide_name,
+            port_fallback: self.port_fallback,
+            discovery: self.discovery,
+            max_sessions: self.max_sessions,
+        }
+    }
+
+    /// Time between presence broadcasts, `None` if disabled.
+    pub fn heartbeat(&self) -> Option<Duration> {
+        match self.heartbeat_interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Apply the settings that affect the look of the GUI.
+    pub fn apply(&self, ctx: &Context) {
+        ctx.set_visuals(self.theme.visuals());
+    }
+
+    /// Show the settings window. Changes take effect immediately, the port on the next connect.
+    pub fn show(&mut self, ctx: &Context, open: &mut bool) {
+        let theme = self.theme;
+        egui::Window::new("Settings")
+            .open(open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("settings").num_columns(2).show(ui, |ui| {
+                    ui.label("Port:");
+                    ui.horizontal(|ui| {
+                        ui.add(DragValue::new(&mut self.port))
+                            .on_hover_text("Used on the next connect");
+                        ui.checkbox(&mut self.port_fallback, "Next if in use")
+                            .on_hover_text("Try the next few ports when this one is taken");
+                    });
+                    ui.end_row();
+                    ui.label("Bind address:");
+                    let valid = self.bind_address.trim().is_empty()
+                        || self.bind_address.trim().parse::<IpAddr>().is_ok();
+                    let edit = egui::TextEdit::singleline(&mut self.bind_address)
+                        .hint_text("All interfaces");
+                    let response = ui.add(edit);
+                    if valid {
+                        response.on_hover_text("Pins the interface used, on the next connect");
+                    } else {
+                        response.on_hover_text("Not an IP address, all interfaces are used");
+                    }
+                    ui.end_row();
+                    ui.label("Default username:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.username)
+                            .char_limit(MAX_USERNAME_LENGTH),
+                    );
+                    ui.end_row();
+                    ui.label("Theme:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.theme, Theme::Dark, "Dark");
+                        ui.radio_value(&mut self.theme, Theme::Light, "Light");
+                    });
+                    ui.end_row();
+                    ui.label("Max message length:");
+                    ui.add(
+                        DragValue::new(&mut self.max_message_length)
+                            .clamp_range(1..=MAX_MESSAGE_LENGTH),
+                    );
+                    ui.end_row();
+                    ui.label("Heartbeat interval:");
+                    ui.add(DragValue::new(&mut self.heartbeat_interval).suffix(" s"))
+                        .on_hover_text("Seconds between presence broadcasts, 0 disables them");
+                    ui.end_row();
+                    ui.label("Sessions per peer:");
+                    ui.add(DragValue::new(&mut self.max_sessions).clamp_range(1..=16))
+                        .on_hover_text(
+                            "Messages to one peer waiting for the one in flight, counting it. \
+                             Used on the next connect.",
+                        );
+                    ui.end_row();
+                    ui.label("Hide name:");
+                    ui.checkbox(&mut self.hide_name, "In broadcasts")
+                        .on_hover_text(
+                            "Send the name encrypted only to peers that answer, used on the next \
+                         connect. Hides it from passive listeners, not from other hosts.",
+                        );
+                    ui.end_row();
+                    ui.label("Discovery:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.discovery, Discovery::Broadcast, "Broadcast")
+                            .on_hover_text("Find peers on the local network");
+                        ui.radio_value(&mut self.discovery, Discovery::Directed, "Directed only")
+                            .on_hover_text(
+                                "Never broadcast, only greet peers added by address. Used on the \
+                                 next connect.",
+                            );
+                        ui.radio_value(&mut self.discovery, Discovery::Mdns, "mDNS")
+                            .on_hover_text(
+                                "Announce the host over multicast DNS and greet the hosts found \
+                                 this way, never broadcast. Used on the next connect.",
+                            );
+                    });
+                    ui.end_row();
+                    ui.label("Notifications:");
+                    ui.checkbox(&mut self.notifications, "When unfocused")
+                        .on_hover_text("Show a system notification for messages in the background");
+                    ui.end_row();
+                    ui.label("Demo:");
+                    ui.checkbox(&mut self.show_demo, "Open on start")
+                        .on_hover_text("Show the cryptography demo when a profile is created");
+                    ui.end_row();
+                    ui.label("Debug:");
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.show_points, "Show curve points")
+                            .on_hover_text(
+                                "Show the points exchanged in the last transfer of each chat",
+                            );
+                        ui.checkbox(&mut self.debug_export, "Session export")
+                            .on_hover_text(
+                                "Copy the parameters of the last session of a chat as JSON for a \
+                                 bug report. Includes the secret scalars, do not share them in use.",
+                            );
+                    });
+                    ui.end_row();
+                    ui.label("Self-test:");
+                    ui.checkbox(&mut self.self_test, "On start").on_hover_text(
+                        "Check the cryptography against known answers before connecting",
+                    );
+                    ui.end_row();
+                    ui.label("Blocked:").on_hover_text(
+                        "Peers whose messages are ignored, block them from the peer list or a chat",
+                    );
+                    self.blocklist.show(ui);
+                    ui.end_row();
+                    ui.label("Decoy:").on_hover_text(
+                        "Sent in place of an empty message field, so one real message can be sent",
+                    );
+                    ui.horizontal(|ui| {
+                        if ui.radio(self.decoy == Decoy::Off, "Off").clicked() {
+                            self.decoy = Decoy::Off;
+                        }
+                        let text = matches!(self.decoy, Decoy::Text(_));
+                        if ui.radio(text, "Text").clicked() && !text {
+                            self.decoy = Decoy::default();
+                        }
+                        if ui.radio(self.decoy == Decoy::Random, "Random").clicked() {
+                            self.decoy = Decoy::Random;
+                        }
+                        if let Decoy::Text(text) = &mut self.decoy {
+                            ui.add(egui::TextEdit::singleline(text).char_limit(MAX_MESSAGE_LENGTH));
+                        }
+                    });
+                    ui.end_row();
+                });
+            });
+
+        if self.theme != theme {
+            self.apply(ctx);
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            port_fallback: true,
+            bind_address: String::new(),
+            username: String::new(),
+            theme: Theme::default(),
+            max_message_length: MAX_MESSAGE_LENGTH,
+            heartbeat_interval: DEFAULT_HEARTBEAT,
+            max_sessions: 1,
+            hide_name: false,
+            discovery: Discovery::default(),
+            notifications: true,
+            decoy: Decoy::default(),
+            show_demo: true,
+            self_test: true,
+            show_points: false,
+            debug_export: false,
+            blocklist: Blocklist::default(),
+        }
+    }
+}
@@ -1,15 +1,21 @@
-use eframe::egui::Ui;
-use local_ip_address::local_ip;
+use eframe::egui::{Button, DragValue, Ui};
 use tracing::error;
 
-use crate::net::{NetworkError, NetworkHost, Username};
+use crate::net::{Event, NetworkError, NetworkHost, NetworkStatus, Username};
 use crate::UiContext;
 
-static PORT: u16 = 12345;
+use super::Settings;
 
 /// The top panel of the GUI.
-#[derive(Debug, Default)]
-pub struct TopPanel(TopPanelInner);
+#[derive(Debug)]
+pub struct TopPanel {
+    inner: TopPanelInner,
+    loopback: bool,
+    /// Port bound instead of the one from the settings.
+    port: Option<u16>,
+    /// Name being edited while connected.
+    rename: Option<String>,
+}
 
 #[derive(Debug)]
 enum TopPanelInner {
@@ -21,20 +27,87 @@ enum Action {
     None,
     Connect(Username),
     Disconnect(String),
+    Cancel(String),
+    Rename(Username),
+    Failed(String),
 }
 
 impl TopPanel {
-    /// Draw the top panel of the GUI.
-    pub fn draw(&mut self, ui: &mut Ui) -> Result<(), NetworkError> {
+    /// Create the top panel with the username entry prefilled.
+    pub fn new(username: String) -> Self {
+        Self {
+            inner: TopPanelInner::Username(username),
+            loopback: false,
+            port: None,
+            rename: None,
+        }
+    }
+
+    /// Create the top panel binding its own port instead of the one from the settings.
+    pub fn with_port(username: String, port: u16) -> Self {
+        Self {
+            inner: TopPanelInner::Username(username),
+            loopback: false,
+            port: Some(port),
+            rename: None,
+        }
+    }
+
+    /// Name of the connected host, or the username entered so far.
+    pub fn name(&self) -> &str {
+        match &self.inner {
+            TopPanelInner::Network(network_host) => network_host.name(),
+            TopPanelInner::Username(username) => username,
+        }
+    }
+
+    /// Draw the top panel of the GUI. Connects on the address and port from the settings,
+    /// unless the panel has its own port.
+    pub fn draw(&mut self, ui: &mut Ui, settings: &Settings) -> Result<(), NetworkError> {
         let mut action = Action::None;
-        ui.horizontal(|ui| match &mut self.0 {
+        ui.horizontal(|ui| match &mut self.inner {
             TopPanelInner::Network(network_host) => {
                 let name = network_host.name();
-                let ip = local_ip()
-                    .map(|ip| ip.to_string())
-                    .unwrap_or("Cannot find address".to_string());
-
-                ui.label(format!("Connected as: {name} ({ip})"));
+                match network_host.status() {
+                    NetworkStatus::Connecting => {
+                        ui.spinner();
+                        ui.label(format!("Connecting as: {name}"));
+                        if ui.button("Cancel").clicked() {
+                            action = Action::Cancel(name.to_string());
+                        }
+                        return;
+                    }
+                    NetworkStatus::Connected(address) => match &mut self.rename {
+                        Some(new_name) => {
+                            ui.label("Rename to:");
+                            ui.text_edit_singleline(new_name);
+                            let valid = Username::try_from(new_name.clone());
+                            let save = ui.add_enabled(valid.is_ok(), Button::new("Save"));
+                            if let (true, Ok(username)) = (save.clicked(), valid) {
+                                action = Action::Rename(username);
+                            }
+                            if ui.button("Cancel").clicked() {
+                                self.rename = None;
+                            }
+                        }
+                        None => {
+                            ui.label(format!("Connected as: {name} ({address})"));
+                            let rename = ui
+                                .button("✏")
+                                .on_hover_text("Change the name without reconnecting");
+                            if rename.clicked() {
+                                self.rename = Some(name.to_string());
+                            }
+                            let copy = ui
+                                .button("📋")
+                                .on_hover_text("Copy address for adding manually");
+                            if copy.clicked() {
+                                ui.output_mut(|output| output.copied_text = address.to_string());
+                            }
+                        }
+                    },
+                    NetworkStatus::Failed => action = Action::Failed(name.to_string()),
+                }
                 if ui.button("Disconnect").clicked() {
                     action = Action::Disconnect(name.to_string());
                 }
@@ -42,6 +115,12 @@ impl TopPanel {
             TopPanelInner::Username(username) => {
                 ui.label("Username:");
                 ui.text_edit_singleline(username);
+                if let Some(port) = &mut self.port {
+                    ui.label("Port:");
+                    ui.add(DragValue::new(port));
+                }
+                ui.checkbox(&mut self.loopback, "Loopback")
+                    .on_hover_text("Chat with a local echo peer without using the network");
                 ui.set_enabled(Username::try_from(username.clone()).is_ok());
                 if ui.button("Connect").clicked() {
                     let mut name = String::new();
@@ -54,13 +133,51 @@ impl TopPanel {
         match action {
             Action::Connect(username) => {
                 let ctx = UiContext::new(ui.ctx().clone());
-                self.0 = TopPanelInner::Network(NetworkHost::new(ctx, username, PORT));
+                let host = if self.loopback {
+                    NetworkHost::new_loopback(ctx, username, settings.network_options())
+                } else {
+                    let mut bind = settings.bind();
+                    if let Some(port) = self.port {
+                        bind.set_port(port);
+                    }
+                    NetworkHost::new(ctx, username, bind, settings.network_options())
+                };
+                self.inner = TopPanelInner::Network(host);
             }
             Action::Disconnect(username) => {
                 let mut inner = TopPanelInner::Username(username);
-                std::mem::swap(&mut self.0, &mut inner);
+                std::mem::swap(&mut self.inner, &mut inner);
+                if let TopPanelInner::Network(network_host) = inner {
+                    network_host.disconnect()?;
+                }
+            }
+            Action::Cancel(username) => {
+                let mut inner = TopPanelInner::Username(username);
+                std::mem::swap(&mut self.inner, &mut inner);
                 if let TopPanelInner::Network(network_host) = inner {
+                    // Disconnecting would block until the socket is bound.
+                    network_host.cancel();
+                }
+            }
+            Action::Rename(username) => {
+                self.rename = None;
+                if let TopPanelInner::Network(network_host) = &mut self.inner {
+                    network_host.set_name(username)?;
+                }
+            }
+            Action::Failed(username) => {
+                let mut inner = TopPanelInner::Username(username);
+                std::mem::swap(&mut self.inner, &mut inner);
+                if let TopPanelInner::Network(mut network_host) = inner {
+                    // Report the reason the task stopped, usually a failed bind.
+                    let error = std::iter::from_fn(|| network_host.poll_event())
+                        .find_map(|event| match event {
+                            Event::Error(error) => Some(error),
+                            _ => None,
+                        })
+                        .unwrap_or(NetworkError::TaskClosed);
                     network_host.disconnect()?;
+                    return Err(error);
                 }
             }
             Action::None => {}
@@ -71,7 +188,7 @@ impl TopPanel {
 
     /// Get the network host if it is connected.
     pub fn get_network_host(&mut self) -> Option<&mut NetworkHost> {
-        if let TopPanelInner::Network(network_host) = &mut self.0 {
+        if let TopPanelInner::Network(network_host) = &mut self.inner {
             Some(network_host)
         } else {
             None
@@ -81,7 +198,7 @@ impl TopPanel {
     /// Clean up resources on exit.
     pub fn on_exit(&mut self) {
         let mut host = TopPanelInner::Username(String::new());
-        std::mem::swap(&mut host, &mut self.0);
+        std::mem::swap(&mut host, &mut self.inner);
         if let TopPanelInner::Network(host) = host {
             if let Err(err) = host.disconnect() {
                 error!("{err}");
@@ -0,0 +1,113 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use humantime::format_rfc3339_seconds;
+use rfd::FileDialog;
+use serde::Serialize;
+
+use crate::net::Peer;
+
+/// File format of an exported conversation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Format {
+    Text,
+    Json,
+}
+
+/// Exported message of a conversation.
+#[derive(Debug, Serialize)]
+pub(super) struct Entry<'a> {
+    #[serde(serialize_with = "serialize_time")]
+    pub time: SystemTime,
+    pub sender: String,
+    pub text: Vec<&'a str>,
+    pub status: &'static str,
+}
+
+/// Parameters of the last session with a peer, hex encoded, for reporting failed transfers.
+/// Includes secrets, so it is only collected in debug mode.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub(super) struct Session {
+    pub greet: Option<String>,
+    pub response: Option<String>,
+    /// Secret scalar of the transfer, `a` when sending and `b` when receiving.
+    pub scalar: Option<String>,
+    pub ciphertext: Option<[String; 2]>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionExport<'a> {
+    peer: String,
+    address: String,
+    session: &'a Session,
+}
+
+#[derive(Debug, Serialize)]
+struct Transcript<'a> {
+    peer: String,
+    address: String,
+    messages: &'a [Entry<'a>],
+}
+
+/// Ask for a file and write the conversation with the peer to it.
+/// Returns the path written, or `None` if the dialog was cancelled.
+pub(super) fn export(
+    peer: &Peer,
+    entries: &[Entry],
+    format: Format,
+) -> io::Result<Option<PathBuf>> {
+    let (extension, contents) = match format {
+        Format::Text => ("txt", to_text(peer, entries)),
+        Format::Json => ("json", to_json(peer, entries)?),
+    };
+
+    let file_name = format!("conversation-{}.{extension}", peer.address()).replace(':', "_");
+    let Some(path) = FileDialog::new()
+        .set_file_name(file_name)
+        .add_filter(extension, &[extension])
+        .save_file()
+    else {
+        return Ok(None);
+    };
+
+    std::fs::write(&path, contents)?;
+    Ok(Some(path))
+}
+
+fn to_text(peer: &Peer, entries: &[Entry]) -> String {
+    let mut text = format!("Conversation with {peer} at {}\n\n", peer.address());
+    for entry in entries {
+        let time = format_rfc3339_seconds(entry.time);
+        let messages = entry.text.join(" | ");
+        text += &format!("[{time}] {}: {messages} ({})\n", entry.sender, entry.status);
+    }
+    text
+}
+
+fn to_json(peer: &Peer, entries: &[Entry]) -> io::Result<String> {
+    let transcript = Transcript {
+        peer: peer.to_string(),
+        address: peer.address().to_string(),
+        messages: entries,
+    };
+    Ok(serde_json::to_string_pretty(&transcript)?)
+}
+
+/// Serialize the session with the peer to JSON, to be pasted into a bug report.
+pub(super) fn session_to_json(peer: &Peer, session: &Session) -> serde_json::Result<String> {
+    let export = SessionExport {
+        peer: peer.to_string(),
+        address: peer.address().to_string(),
+        session,
+    };
+    serde_json::to_string_pretty(&export)
+}
+
+fn serialize_time<S: serde::Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_rfc3339_seconds(*time))
+}
@@ -1,34 +1,71 @@
+#[cfg(feature = "cli")]
+pub use cli::{run_command, Cli, Command};
 #[cfg(feature = "gui")]
 pub use gui::run;
 #[cfg(feature = "tui")]
 pub use tui::run;
 
+use std::fmt;
+use std::sync::Arc;
+
 #[cfg(all(feature = "gui", feature = "tui"))]
 compile_error!("features `gui` and `tui` are mutually exclusive");
 
+#[cfg(feature = "cli")]
+mod cli;
 #[cfg(feature = "gui")]
 mod gui;
-mod net;
+/// Networking and the oblivious transfer protocol, usable without any user interface.
+pub mod net;
 #[cfg(feature = "tui")]
 mod tui;
 
-#[derive(Debug)]
-struct UiContext {
+/// Run app. Built without a user interface, so this only reports an error.
+#[cfg(not(any(feature = "gui", feature = "tui")))]
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    Err("built without a user interface, enable the `gui` or `tui` feature".into())
+}
+
+/// Handle the network task uses to wake the user interface when events arrive.
+/// Without the `gui` feature it only calls the callback, if one is set.
+#[derive(Default)]
+pub struct UiContext {
     #[cfg(feature = "gui")]
     ctx: eframe::egui::Context,
+    callback: Option<Arc<dyn Fn() + Send + Sync>>,
 }
 
 impl UiContext {
     #[cfg(feature = "gui")]
     fn new(ctx: eframe::egui::Context) -> Self {
-        Self { ctx }
+        Self {
+            ctx,
+            callback: None,
+        }
+    }
+
+    /// Create a context calling the function whenever network events are ready to be polled.
+    pub fn with_callback(callback: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            #[cfg(feature = "gui")]
+            ctx: Default::default(),
+            callback: Some(Arc::new(callback)),
+        }
     }
 
-    #[cfg(feature = "gui")]
     fn request_repaint(&self) {
+        #[cfg(feature = "gui")]
         self.ctx.request_repaint();
+        if let Some(callback) = &self.callback {
+            callback();
+        }
     }
+}
 
-    #[cfg(feature = "tui")]
-    fn request_repaint(&self) {}
+impl fmt::Debug for UiContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UiContext")
+            .field("callback", &self.callback.is_some())
+            .finish_non_exhaustive()
+    }
 }
@@ -1,8 +1,23 @@
+use tracing_subscriber::EnvFilter;
+
+static DEFAULT_LOG_FILTER: &str = "info"; // Used when RUST_LOG is unset or invalid
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
     let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::TRACE)
+        .with_env_filter(filter)
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
+    #[cfg(feature = "cli")]
+    {
+        use clap::Parser;
+
+        if let Some(command) = oblivious_transfer::Cli::parse().command {
+            return oblivious_transfer::run_command(command);
+        }
+    }
+
     oblivious_transfer::run()
 }
@@ -1,26 +1,34 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::ops::{Deref, Range};
+use std::ops::Deref;
 
-#[cfg(feature = "gui")]
-use eframe::egui::TextBuffer;
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+/// Maximum length of a username in characters, after normalization.
+pub const MAX_USERNAME_LENGTH: usize = 100;
+
+/// Maximum length of a message in bytes.
+pub const MAX_MESSAGE_LENGTH: usize = 1000;
 
 /// Error in creating username.
 #[derive(Debug, Error)]
 pub enum UsernameError {
     #[error("Username cannot be empty")]
     Empty,
-    #[error("Username cannot have more than 100 characters")]
+    #[error("Username cannot have more than {MAX_USERNAME_LENGTH} characters")]
     TooLong,
 }
 
-/// Peer username. Has between 1 and 100 characters.
+/// Peer username. Has between 1 and 100 characters and is stored in Unicode NFC form.
 #[repr(transparent)]
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Username(String);
 
 impl Username {
     /// Create a new username. Username must have between 1 and 100 characters.
+    /// The name is normalized to Unicode NFC so canonically equal names compare equal.
     pub fn new(name: String) -> Result<Self, UsernameError> {
         name.try_into()
     }
@@ -36,9 +44,10 @@ impl TryFrom<String> for Username {
     type Error = UsernameError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        match value.len() {
+        let value: String = value.nfc().collect();
+        match value.chars().count() {
             0 => Err(UsernameError::Empty),
-            1..=100 => Ok(Self(value)),
+            1..=MAX_USERNAME_LENGTH => Ok(Self(value)),
             _ => Err(UsernameError::TooLong),
         }
     }
@@ -59,7 +68,10 @@ impl std::fmt::Display for Username {
 }
 
 /// Peer to peer network user.
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+///
+/// Peers are compared, ordered and hashed by address only. The name is learned later and can
+/// change, so the same host with and without its name must not count as two peers.
+#[derive(Clone, Debug)]
 pub struct Peer {
     address: SocketAddr,
     name: Option<Username>,
@@ -75,7 +87,7 @@ impl Peer {
     }
 
     /// Create a new peer with name.
-    pub(super) fn new_with_name(address: SocketAddr, name: Username) -> Self {
+    pub fn new_with_name(address: SocketAddr, name: Username) -> Self {
         Self {
             address,
             name: Some(name),
@@ -86,6 +98,37 @@ impl Peer {
     pub fn address(&self) -> SocketAddr {
         self.address
     }
+
+    /// Get the name of the peer, if it is known.
+    pub fn name(&self) -> Option<&Username> {
+        self.name.as_ref()
+    }
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address.hash(state);
+    }
+}
+
+impl PartialOrd for Peer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Peer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.address.cmp(&other.address)
+    }
 }
 
 impl std::fmt::Display for Peer {
@@ -100,7 +143,7 @@ impl std::fmt::Display for Peer {
 /// Error in creating a message.
 #[derive(Debug, Error)]
 pub enum UserMessageError {
-    #[error("Message cannot have more than 1000 characters")]
+    #[error("Message cannot have more than {MAX_MESSAGE_LENGTH} characters")]
     TooLong,
 }
 
@@ -127,7 +170,7 @@ impl TryFrom<String> for UserMessage {
     type Error = UserMessageError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.len() <= 1000 {
+        if value.len() <= MAX_MESSAGE_LENGTH {
             Ok(Self(value))
         } else {
             Err(UserMessageError::TooLong)
@@ -141,22 +184,44 @@ impl std::fmt::Display for UserMessage {
     }
 }
 
-#[cfg(feature = "gui")]
-impl TextBuffer for UserMessage {
-    fn is_mutable(&self) -> bool {
-        true
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_is_identified_by_address() {
+        let address = SocketAddr::from(([10, 0, 0, 1], 12345));
+        let name = Username::new("alice".to_string()).unwrap();
+        let named = Peer::new_with_name(address, name);
+
+        assert_eq!(Peer::new(address), named);
+        assert_ne!(Peer::new(SocketAddr::from(([10, 0, 0, 2], 12345))), named);
+        let peers: std::collections::HashSet<_> = [Peer::new(address), named].into();
+        assert_eq!(peers.len(), 1);
     }
 
-    fn as_str(&self) -> &str {
-        self.0.as_str()
+    #[test]
+    fn username_is_normalized() {
+        let composed = Username::new("Zo\u{00EB}".to_string()).unwrap();
+        let decomposed = Username::new("Zoe\u{0308}".to_string()).unwrap();
+
+        assert_eq!(composed, decomposed);
+        assert_eq!(&*decomposed, "Zo\u{00EB}");
     }
 
-    fn insert_text(&mut self, text: &str, char_index: usize) -> usize {
-        let text = &text[..text.len().min(1000 - self.0.len())];
-        self.0.insert_text(text, char_index)
+    #[test]
+    fn username_length_counts_characters() {
+        assert!(Username::new("名".repeat(MAX_USERNAME_LENGTH)).is_ok());
+        assert!(matches!(
+            Username::new("名".repeat(MAX_USERNAME_LENGTH + 1)),
+            Err(UsernameError::TooLong)
+        ));
     }
 
-    fn delete_char_range(&mut self, char_range: Range<usize>) {
-        self.0.delete_char_range(char_range);
+    #[test]
+    fn username_length_is_checked_after_normalization() {
+        let name = "e\u{0301}".repeat(MAX_USERNAME_LENGTH / 2);
+        assert!(name.len() > MAX_USERNAME_LENGTH);
+        assert!(Username::new(name).is_ok());
     }
 }
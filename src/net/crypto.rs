@@ -1,11 +1,15 @@
+#[cfg(feature = "signing")]
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
 use libaes::Cipher;
-use p256::elliptic_curve::{sec1::ToEncodedPoint, Field};
+use p256::elliptic_curve::{ops::MulByGenerator, sec1::ToEncodedPoint, Field};
 use p256::{ProjectivePoint as CurvePoint, Scalar};
-use rand::{random, thread_rng};
+use rand::rngs::StdRng;
+use rand::{thread_rng, CryptoRng, RngCore, SeedableRng};
 use sha2::{Digest, Sha256};
 use thiserror::Error;
+use zeroize::Zeroizing;
 
-use super::UserMessage;
+use super::{MessageError, UserMessage};
 
 /// Error in cryptography protocol.
 #[derive(Debug, Error)]
@@ -14,65 +18,608 @@ pub enum CryptoError {
     InvalidMessage,
     #[error("Received invalid curve point")]
     InvalidPoint,
+    #[error("Message signature is invalid")]
+    BadSignature,
+    #[error("Message does not belong to the current session")]
+    Replay,
+    #[error("Message was meant for us but could not be decrypted")]
+    Undecryptable,
+    #[error("No cipher suite is supported by both hosts")]
+    NoCommonSuite,
+    #[error("Self-test failed: {0}")]
+    SelfTest(&'static str),
+}
+
+/// Curve and cipher used by a transfer. Greetings list the suites a host supports, the data of a
+/// transfer names the one it was sent with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Suite {
+    /// Oblivious transfer on P-256 with AES-256-CBC, the original protocol.
+    P256Aes256Cbc,
+}
+
+impl Suite {
+    /// Pick the strongest suite supported by this host that the peer also lists.
+    pub fn negotiate(theirs: &[Suite]) -> Result<Suite, CryptoError> {
+        SUPPORTED_SUITES
+            .iter()
+            .copied()
+            .find(|suite| theirs.contains(suite))
+            .ok_or(CryptoError::NoCommonSuite)
+    }
+
+    /// Encrypt with the cipher of the suite.
+    fn encrypt(self, key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+        match self {
+            Suite::P256Aes256Cbc => encrypt(key, data),
+        }
+    }
+
+    /// Decrypt with the cipher of the suite.
+    fn decrypt(self, key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+        match self {
+            Suite::P256Aes256Cbc => decrypt(key, data),
+        }
+    }
+}
+
+impl From<Suite> for u8 {
+    fn from(value: Suite) -> Self {
+        match value {
+            Suite::P256Aes256Cbc => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for Suite {
+    type Error = MessageError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Suite::P256Aes256Cbc),
+            _ => Err(MessageError::UnknownSuite(value)),
+        }
+    }
+}
+
+/// Suites supported by this host, strongest first.
+pub(super) static SUPPORTED_SUITES: &[Suite] = &[Suite::P256Aes256Cbc];
+
+/// Ed25519 public key identifying a host.
+pub type PublicKey = [u8; 32];
+
+/// Ed25519 signature of the data messages.
+pub type Signature = [u8; 64];
+
+/// Size of the session nonce prefixed to each message before encryption.
+static NONCE_SIZE: usize = 16;
+
+/// AES block size, every padded ciphertext is a non-empty multiple of it.
+static BLOCK_SIZE: usize = 16;
+
+/// SEC1 encoding of points sent on the wire and hashed into the session nonce and transcript:
+/// compressed, 33 bytes.
+pub(super) static WIRE_COMPRESSED: bool = true;
+
+/// SEC1 encoding of the shared point hashed into a key: uncompressed, 65 bytes. It is part of the
+/// protocol, so matching it to the wire encoding would break key agreement with other hosts.
+static KEY_COMPRESSED: bool = false;
+
+/// Hash of the points and ciphertexts exchanged in a transfer, compared out-of-band to detect a
+/// man in the middle.
+pub type TranscriptHash = [u8; 32];
+
+/// Signing identity of the host.
+#[cfg(feature = "signing")]
+#[derive(Debug)]
+pub(super) struct Identity(SigningKey);
+
+#[cfg(feature = "signing")]
+impl Identity {
+    /// Generate a new random identity.
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut thread_rng()))
+    }
+
+    /// Get the public key of the identity.
+    pub fn public_key(&self) -> PublicKey {
+        self.0.verifying_key().to_bytes()
+    }
+
+    /// Sign encrypted data messages.
+    pub fn sign(&self, m0: &[u8], m1: &[u8]) -> Signature {
+        self.0.sign(&signed_bytes(m0, m1)).to_bytes()
+    }
+}
+
+/// Verify the signature of encrypted data messages.
+#[cfg(feature = "signing")]
+pub(super) fn verify(
+    key: &PublicKey,
+    m0: &[u8],
+    m1: &[u8],
+    signature: &Signature,
+) -> Result<(), CryptoError> {
+    let key = VerifyingKey::from_bytes(key).map_err(|_| CryptoError::BadSignature)?;
+    let signature = ed25519_dalek::Signature::from_bytes(signature);
+    key.verify(&signed_bytes(m0, m1), &signature)
+        .map_err(|_| CryptoError::BadSignature)
+}
+
+#[cfg(feature = "signing")]
+fn signed_bytes(m0: &[u8], m1: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 + m0.len() + m1.len());
+    bytes.extend_from_slice(&(m0.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(m0);
+    bytes.extend_from_slice(m1);
+    bytes
+}
+
+/// Key pair of the host used to agree on keys encrypting names sent to peers.
+///
+/// The key exchange is unauthenticated: it hides names from passive listeners, but any host that
+/// answers a greeting learns the name just like it would from a cleartext broadcast.
+#[derive(Debug)]
+pub(super) struct NameSecret(Zeroizing<Scalar>, CurvePoint);
+
+impl NameSecret {
+    /// Generate a new random key pair.
+    pub fn generate() -> Self {
+        let secret = Zeroizing::new(Scalar::random(&mut thread_rng()));
+        let point = CurvePoint::mul_by_generator(&*secret);
+        Self(secret, point)
+    }
+
+    /// Get the point sent to peers.
+    pub fn public(&self) -> CurvePoint {
+        self.1
+    }
+
+    /// Encrypt a name for the peer that sent the point.
+    pub fn encrypt(&self, other: CurvePoint, name: &[u8]) -> Vec<u8> {
+        encrypt(&into_key(other * *self.0), name)
+    }
+
+    /// Decrypt a name sent by the peer that sent the point.
+    pub fn decrypt(&self, other: CurvePoint, name: &[u8]) -> Vec<u8> {
+        decrypt(&into_key(other * *self.0), name)
+    }
 }
 
 /// State of the connection cryptography.
+///
+/// The secret scalar and the derived key are wiped when the state is dropped. This does not cover
+/// copies made outside of it, such as the expanded key schedule inside the AES cipher or
+/// intermediate curve points.
 #[derive(Debug)]
-pub(super) enum MessageState {
-    GreetSent(Scalar, CurvePoint, UserMessage, UserMessage),
-    GreetReceived([u8; 32], bool),
+pub enum MessageState {
+    GreetSent(Zeroizing<Scalar>, CurvePoint, UserMessage, UserMessage),
+    GreetReceived(Zeroizing<[u8; 32]>, bool, CurvePoint, CurvePoint),
 }
 
 impl MessageState {
-    /// Handle messages sent by the client.
+    /// Handle messages sent by the client. Returns the greeting point to send to the receiver.
     pub fn send_message(m0: UserMessage, m1: UserMessage, a: Option<Scalar>) -> (CurvePoint, Self) {
-        let a = a.unwrap_or_else(|| Scalar::random(thread_rng()));
-        let point = CurvePoint::GENERATOR * a;
+        Self::send_message_with_rng(m0, m1, a, &mut thread_rng())
+    }
+
+    /// Handle messages sent by the client, drawing the scalar from the given RNG.
+    pub fn send_message_with_rng(
+        m0: UserMessage,
+        m1: UserMessage,
+        a: Option<Scalar>,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> (CurvePoint, Self) {
+        let a = Zeroizing::new(a.unwrap_or_else(|| Scalar::random(&mut *rng)));
+        let point = CurvePoint::mul_by_generator(&*a);
         (point, MessageState::GreetSent(a, point, m0, m1))
     }
 
-    /// On greeting message.
-    pub fn on_greeting(point: CurvePoint) -> (CurvePoint, Self) {
-        let b = Scalar::random(thread_rng());
-        let c = random();
+    /// On greeting message, choosing to receive the second message if `c` is set.
+    pub fn on_greeting(point: CurvePoint, c: bool) -> (CurvePoint, Self) {
+        Self::on_greeting_with_rng(point, c, &mut thread_rng())
+    }
 
-        let response = if c {
-            point + CurvePoint::GENERATOR * b
-        } else {
-            CurvePoint::GENERATOR * b
-        };
+    /// On greeting message, drawing the scalar from the given RNG.
+    pub fn on_greeting_with_rng(
+        point: CurvePoint,
+        c: bool,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> (CurvePoint, Self) {
+        Self::on_greeting_with_scalar(point, c, Scalar::random(&mut *rng))
+    }
+
+    /// On greeting message, with the given secret scalar.
+    pub fn on_greeting_with_scalar(point: CurvePoint, c: bool, b: Scalar) -> (CurvePoint, Self) {
+        let b = Zeroizing::new(b);
 
-        (response, Self::GreetReceived(into_key(point * b), c))
+        let b_point = CurvePoint::mul_by_generator(&*b);
+        let response = if c { point + b_point } else { b_point };
+
+        let key = into_key(point * *b);
+        (response, Self::GreetReceived(key, c, point, response))
     }
 
-    /// On greeting response.
-    pub fn on_response(self, other: CurvePoint) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    /// On greeting response. Returns the messages encrypted with the suite and the transcript
+    /// hash.
+    ///
+    /// For [`Suite::P256Aes256Cbc`], with the greeting `A = aG` and the response `B`, each
+    /// ciphertext is the 16-byte session nonce followed by AES-256-CBC with PKCS#7 padding of the
+    /// nonce and the UTF-8 message. The key of `e0` is the SHA-256 of `aB` and of `e1` the SHA-256
+    /// of `a(B - A)`, both SEC1 uncompressed, and the IV is the first 16 bytes of the key. The
+    /// nonce is the start of the SHA-256 of `"OTMP session"`, `A` and `B`, the points SEC1
+    /// compressed as by [`point_to_bytes`](super::point_to_bytes).
+    pub fn on_response(
+        self,
+        suite: Suite,
+        other: CurvePoint,
+    ) -> Result<(Vec<u8>, Vec<u8>, TranscriptHash), CryptoError> {
         match self {
             MessageState::GreetSent(a, point, m0, m1) => {
-                let key0 = into_key(other * a);
-                let key1 = into_key((other - point) * a);
-                Ok((
-                    Cipher::new_256(&key0).cbc_encrypt(&key0, m0.as_bytes()),
-                    Cipher::new_256(&key1).cbc_encrypt(&key1, m1.as_bytes()),
-                ))
+                let key0 = into_key(other * *a);
+                let key1 = into_key((other - point) * *a);
+                let nonce = session_nonce(point, other);
+                let seal = |key, message: &UserMessage| {
+                    let sealed = suite.encrypt(key, &[&nonce[..], message.as_bytes()].concat());
+                    [&nonce[..], &sealed].concat()
+                };
+                let (e0, e1) = (seal(&key0, &m0), seal(&key1, &m1));
+                let hash = transcript_hash(point, other, &e0, &e1);
+                Ok((e0, e1, hash))
             }
-            MessageState::GreetReceived(_, _) => Err(CryptoError::InvalidMessage),
+            MessageState::GreetReceived(..) => Err(CryptoError::InvalidMessage),
         }
     }
 
-    /// On messages received.
-    pub fn on_messages(self, m0: Vec<u8>, m1: Vec<u8>) -> Result<String, CryptoError> {
+    /// On messages received, encrypted with the suite. Returns the chosen message and the
+    /// transcript hash.
+    /// A message that does not start with this session's nonce, such as one replayed from an
+    /// earlier session, fails with [`CryptoError::Replay`] and leaves the session waiting for its
+    /// data. One that carries the nonce but does not open to it and valid text, such as one
+    /// encrypted under another key or corrupted, fails with [`CryptoError::Undecryptable`].
+    pub fn on_messages(
+        &self,
+        suite: Suite,
+        m0: Vec<u8>,
+        m1: Vec<u8>,
+    ) -> Result<(String, TranscriptHash), CryptoError> {
         match self {
             MessageState::GreetSent(_, _, _, _) => Err(CryptoError::InvalidMessage),
-            MessageState::GreetReceived(key, c) => {
-                let ciphertext = if c { m1 } else { m0 };
-                let decoded = Cipher::new_256(&key).cbc_decrypt(&key, &ciphertext);
-                String::from_utf8(decoded).map_err(|_| CryptoError::InvalidMessage)
+            MessageState::GreetReceived(key, c, point, response) => {
+                let hash = transcript_hash(*point, *response, &m0, &m1);
+                let nonce = session_nonce(*point, *response);
+                let ciphertext = if *c { m1 } else { m0 };
+                let sealed = ciphertext
+                    .strip_prefix(&nonce[..])
+                    .ok_or(CryptoError::Replay)?;
+                let decoded = suite.decrypt(key, sealed);
+                let message = decoded
+                    .strip_prefix(&nonce[..])
+                    .ok_or(CryptoError::Undecryptable)?;
+                let message =
+                    String::from_utf8(message.to_vec()).map_err(|_| CryptoError::Undecryptable)?;
+                Ok((message, hash))
             }
         }
     }
 }
 
-fn into_key(point: CurvePoint) -> [u8; 32] {
-    Sha256::digest(point.to_encoded_point(false).as_bytes()).into()
+/// Known answers of the self-test: the ciphertext of `SELF_TEST_MESSAGES[0]` under the key of the
+/// generator point and the transcript hash of a transfer with fixed scalars.
+static SELF_TEST_CIPHERTEXT: &str =
+    "f6149b89f4c913a51eb2281f84685a6e6b26ae740823eec77be4f7db5c93ba33";
+static SELF_TEST_TRANSCRIPT: &str =
+    "68a95f20de3b8ea5388effba20412850e677a09e7d23bbb75e32b5a1b7428ddb";
+static SELF_TEST_MESSAGES: [&str; 2] = ["OTMP self-test 0", "OTMP self-test 1"];
+static SELF_TEST_SEED: u64 = 0x4F544D50; // "OTMP"
+
+/// Run an encryption and a transfer for both choices with fixed inputs, checking them against known
+/// answers. Catches a broken build or a mismatched dependency before any real message is sent.
+pub fn self_test() -> Result<(), CryptoError> {
+    let key = into_key(CurvePoint::GENERATOR);
+    let plaintext = SELF_TEST_MESSAGES[0].as_bytes();
+    let ciphertext = encrypt(&key, plaintext);
+    if hex::encode(&ciphertext) != SELF_TEST_CIPHERTEXT {
+        return Err(CryptoError::SelfTest("AES ciphertext does not match"));
+    }
+    if decrypt(&key, &ciphertext) != plaintext {
+        return Err(CryptoError::SelfTest("AES round-trip does not match"));
+    }
+
+    let message = |index: usize| {
+        UserMessage::try_from(SELF_TEST_MESSAGES[index].to_string())
+            .map_err(|_| CryptoError::SelfTest("test message is too long"))
+    };
+    for c in [false, true] {
+        let a = Scalar::from(SELF_TEST_SEED);
+        let (point, sender) = MessageState::send_message(message(0)?, message(1)?, Some(a));
+        let mut rng = StdRng::seed_from_u64(SELF_TEST_SEED);
+        let (response, receiver) = MessageState::on_greeting_with_rng(point, c, &mut rng);
+        let suite = Suite::P256Aes256Cbc;
+        let (e0, e1, sent_hash) = sender.on_response(suite, response)?;
+        let (message, received_hash) = receiver.on_messages(suite, e0, e1)?;
+        if message != SELF_TEST_MESSAGES[c as usize] {
+            return Err(CryptoError::SelfTest(
+                "transfer delivered the wrong message",
+            ));
+        }
+        if sent_hash != received_hash {
+            return Err(CryptoError::SelfTest("transcript hashes differ"));
+        }
+        if !c && hex::encode(sent_hash) != SELF_TEST_TRANSCRIPT {
+            return Err(CryptoError::SelfTest("transcript hash does not match"));
+        }
+    }
+    Ok(())
+}
+
+/// Nonce identifying the session, derived from the fresh points of its handshake. It is sent in
+/// front of each message to tell a replayed one apart, and encrypted with it, so a packet from an
+/// earlier session cannot be made to carry it.
+fn session_nonce(greet: CurvePoint, response: CurvePoint) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"OTMP session");
+    hasher.update(greet.to_encoded_point(WIRE_COMPRESSED).as_bytes());
+    hasher.update(response.to_encoded_point(WIRE_COMPRESSED).as_bytes());
+    hasher.finalize()[..NONCE_SIZE].to_vec()
+}
+
+static SAS_MODULUS: u32 = 1_000_000; // Six digits, short enough to read aloud
+
+/// Short authentication string of a handshake, read aloud by both users to detect a man in the
+/// middle, who would have exchanged different points with each of them.
+pub fn short_authentication_string(greet: CurvePoint, response: CurvePoint) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"OTMP sas");
+    hasher.update(greet.to_encoded_point(WIRE_COMPRESSED).as_bytes());
+    hasher.update(response.to_encoded_point(WIRE_COMPRESSED).as_bytes());
+    let hash = hasher.finalize();
+    let value = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+    format!("{:06}", value % SAS_MODULUS)
+}
+
+/// Hash the greeting and response points with both ciphertexts, each ciphertext prefixed by its
+/// length so the boundary between them is unambiguous.
+fn transcript_hash(
+    greet: CurvePoint,
+    response: CurvePoint,
+    e0: &[u8],
+    e1: &[u8],
+) -> TranscriptHash {
+    let mut hasher = Sha256::new();
+    hasher.update(b"OTMP transcript");
+    hasher.update(greet.to_encoded_point(WIRE_COMPRESSED).as_bytes());
+    hasher.update(response.to_encoded_point(WIRE_COMPRESSED).as_bytes());
+    for ciphertext in [e0, e1] {
+        hasher.update((ciphertext.len() as u32).to_be_bytes());
+        hasher.update(ciphertext);
+    }
+    hasher.finalize().into()
+}
+
+/// Derive a symmetric key from a shared curve point.
+pub fn into_key(point: CurvePoint) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(Sha256::digest(point.to_encoded_point(KEY_COMPRESSED).as_bytes()).into())
+}
+
+/// Encrypt data with a derived key. Shared by the network and the demo so they cannot drift.
+/// Empty data is padded to a full block, so the ciphertext is never empty.
+pub fn encrypt(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    Cipher::new_256(key).cbc_encrypt(key, data)
+}
+
+/// Decrypt data with a derived key. Returns an empty vector if the padding is invalid.
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    // Not a padded ciphertext, rejected before it reaches the cipher.
+    if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+        return Vec::new();
+    }
+    Cipher::new_256(key).cbc_decrypt(key, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SUITE: Suite = Suite::P256Aes256Cbc;
+
+    fn message() -> UserMessage {
+        UserMessage::try_from("message".to_string()).unwrap()
+    }
+
+    #[test]
+    fn seeded_rng_is_deterministic() {
+        let (a0, _) = MessageState::send_message_with_rng(
+            message(),
+            message(),
+            None,
+            &mut StdRng::seed_from_u64(1),
+        );
+        let (a1, _) = MessageState::send_message_with_rng(
+            message(),
+            message(),
+            None,
+            &mut StdRng::seed_from_u64(1),
+        );
+        assert_eq!(a0, a1);
+
+        let (b0, _) = MessageState::on_greeting_with_rng(a0, true, &mut StdRng::seed_from_u64(2));
+        let (b1, _) = MessageState::on_greeting_with_rng(a0, true, &mut StdRng::seed_from_u64(2));
+        assert_eq!(b0, b1);
+    }
+
+    #[test]
+    fn generator_multiplication_matches() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..16 {
+            let scalar = Scalar::random(&mut rng);
+            assert_eq!(
+                CurvePoint::mul_by_generator(&scalar),
+                CurvePoint::GENERATOR * scalar
+            );
+        }
+    }
+
+    #[test]
+    fn name_secret_agrees_on_key() {
+        let alice = NameSecret::generate();
+        let bob = NameSecret::generate();
+        let eve = NameSecret::generate();
+
+        let encrypted = alice.encrypt(bob.public(), b"alice");
+        assert_eq!(bob.decrypt(alice.public(), &encrypted), b"alice");
+        assert_ne!(eve.decrypt(alice.public(), &encrypted), b"alice");
+    }
+
+    #[test]
+    fn receiver_recovers_only_the_chosen_message() {
+        let messages = ["first message", "second message"];
+        let a = Scalar::from(42u64);
+
+        for choice in [false, true] {
+            let m0 = UserMessage::try_from(messages[0].to_string()).unwrap();
+            let m1 = UserMessage::try_from(messages[1].to_string()).unwrap();
+            let (point, sender) = MessageState::send_message(m0, m1, Some(a));
+            let (response, receiver) = MessageState::on_greeting(point, choice);
+            let MessageState::GreetReceived(key, _, greet, reply) = &receiver else {
+                unreachable!();
+            };
+            let other = MessageState::GreetReceived(key.clone(), !choice, *greet, *reply);
+
+            let (e0, e1, sent_hash) = sender.on_response(SUITE, response).unwrap();
+            let (chosen, received_hash) =
+                receiver.on_messages(SUITE, e0.clone(), e1.clone()).unwrap();
+            assert_eq!(chosen, messages[choice as usize]);
+            assert_eq!(sent_hash, received_hash);
+
+            // The receiver's key does not open the other message.
+            let other = other.on_messages(SUITE, e0, e1).map(|(message, _)| message);
+            assert_ne!(other.ok().as_deref(), Some(messages[!choice as usize]));
+        }
+    }
+
+    #[test]
+    fn other_session_messages_are_replays() {
+        let session = || {
+            let (point, sender) = MessageState::send_message(message(), message(), None);
+            let (response, receiver) = MessageState::on_greeting(point, false);
+            let (e0, e1, _) = sender.on_response(SUITE, response).unwrap();
+            (receiver, e0, e1)
+        };
+        let (_, old0, old1) = session();
+        let (receiver, e0, e1) = session();
+
+        assert!(matches!(
+            receiver.on_messages(SUITE, old0.clone(), old1),
+            Err(CryptoError::Replay)
+        ));
+
+        // Carrying the nonce of this session, the old data no longer opens.
+        let forged = [&e0[..NONCE_SIZE], &old0[NONCE_SIZE..]].concat();
+        assert!(matches!(
+            receiver.on_messages(SUITE, forged.clone(), forged),
+            Err(CryptoError::Undecryptable)
+        ));
+
+        let (received, _) = receiver.on_messages(SUITE, e0, e1).unwrap();
+        assert_eq!(received, "message");
+    }
+
+    #[test]
+    fn empty_messages_are_transferred() {
+        let (point, sender) =
+            MessageState::send_message(UserMessage::default(), UserMessage::default(), None);
+        let (response, receiver) = MessageState::on_greeting(point, true);
+        let (e0, e1, _) = sender.on_response(SUITE, response).unwrap();
+
+        let (message, _) = receiver.on_messages(SUITE, e0, e1).unwrap();
+        assert_eq!(message, "");
+    }
+
+    #[test]
+    fn empty_data_round_trip() {
+        let key = into_key(CurvePoint::GENERATOR);
+        let ciphertext = encrypt(&key, &[]);
+
+        assert_eq!(ciphertext.len(), BLOCK_SIZE);
+        assert!(decrypt(&key, &ciphertext).is_empty());
+        assert!(decrypt(&key, &[]).is_empty());
+        assert!(decrypt(&key, &vec![0; BLOCK_SIZE - 1]).is_empty());
+    }
+
+    #[test]
+    fn sas_matches_on_both_sides() {
+        let (point, _) = MessageState::send_message(message(), message(), None);
+        let (response, _) = MessageState::on_greeting(point, false);
+        let (other, _) = MessageState::on_greeting(point, false);
+
+        let sas = short_authentication_string(point, response);
+        assert_eq!(sas.len(), 6);
+        assert!(sas.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(sas, short_authentication_string(point, response));
+        assert_ne!(sas, short_authentication_string(point, other));
+    }
+
+    #[test]
+    fn self_test_passes() {
+        self_test().unwrap();
+    }
+
+    #[test]
+    fn suite_negotiation() {
+        assert_eq!(
+            Suite::negotiate(SUPPORTED_SUITES).unwrap(),
+            Suite::P256Aes256Cbc
+        );
+        assert!(matches!(
+            Suite::negotiate(&[]),
+            Err(CryptoError::NoCommonSuite)
+        ));
+        assert!(matches!(
+            Suite::try_from(0xFF),
+            Err(MessageError::UnknownSuite(0xFF))
+        ));
+    }
+
+    #[test]
+    fn ciphertext_layout() {
+        let message = |text: &str| UserMessage::try_from(text.to_string()).unwrap();
+        let a = Scalar::from(7u64);
+        let (point, sender) = MessageState::send_message(message("m0"), message("m1"), Some(a));
+        let (response, _) = MessageState::on_greeting(point, false);
+        let (e0, e1, _) = sender.on_response(SUITE, response).unwrap();
+
+        let nonce = session_nonce(point, response);
+        for (ciphertext, shared, text) in [(e0, response, "m0"), (e1, response - point, "m1")] {
+            assert_eq!(ciphertext.len(), NONCE_SIZE + 2 * BLOCK_SIZE);
+            let (prefix, sealed) = ciphertext.split_at(NONCE_SIZE);
+            assert_eq!(prefix, nonce);
+            let plaintext = decrypt(&into_key(shared * a), sealed);
+            assert_eq!(plaintext, [&nonce[..], text.as_bytes()].concat());
+        }
+    }
+
+    #[test]
+    fn invalid_text_is_undecryptable() {
+        let a = Scalar::from(7u64);
+        let (point, _) = MessageState::send_message(message(), message(), Some(a));
+        let (response, receiver) = MessageState::on_greeting(point, false);
+
+        let nonce = session_nonce(point, response);
+        let sealed = encrypt(&into_key(response * a), &[&nonce[..], &[0xFF]].concat());
+        let e0 = [&nonce[..], &sealed].concat();
+        assert!(matches!(
+            receiver.on_messages(SUITE, e0.clone(), e0),
+            Err(CryptoError::Undecryptable)
+        ));
+    }
+
+    #[test]
+    fn key_hashes_uncompressed_point() {
+        let key = into_key(CurvePoint::GENERATOR);
+        assert_eq!(
+            hex::encode(*key),
+            "698bea63dc44a344663ff1429aea10842df27b6b991ef25866b2c6c02cdcc5be"
+        );
+    }
 }
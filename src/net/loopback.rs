@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use tokio::select;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::info;
+
+use super::{
+    Message, MessageState, NetworkError, Suite, Transport, UserMessage, Username, SUPPORTED_SUITES,
+};
+
+static LOCAL_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+/// Address of the simulated peer, which sends back every message it receives.
+pub static ECHO_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 1);
+static ECHO_NAME: &str = "Echo";
+
+/// In-process transport with a simulated peer that echoes every received message back.
+#[derive(Debug)]
+pub(super) struct Loopback {
+    states: HashMap<SocketAddr, MessageState>,
+    sender: UnboundedSender<(Message, SocketAddr)>,
+    receiver: UnboundedReceiver<(Message, SocketAddr)>,
+}
+
+impl Loopback {
+    /// Create a new loopback transport.
+    pub fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Self {
+            states: HashMap::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Queue a message sent by the simulated peer.
+    fn reply(&self, message: Message, address: SocketAddr) -> Result<(), NetworkError> {
+        self.sender
+            .send((message, address))
+            .map_err(|_| NetworkError::TaskClosed)
+    }
+
+    /// Handle a message as the simulated peer at the address.
+    fn on_message(&mut self, message: Message, address: SocketAddr) -> Result<(), NetworkError> {
+        match message {
+            Message::BroadcastGreet(..) | Message::HiddenGreet(_) => {
+                let name = Username::new(ECHO_NAME.to_string()).expect("valid echo name");
+                let suites = SUPPORTED_SUITES.to_vec();
+                self.reply(Message::BroadcastResponse(name, None, suites), address)
+            }
+            Message::BroadcastResponse(..)
+            | Message::BroadcastBye
+            | Message::HiddenResponse(_, _)
+            | Message::HiddenReveal(_, _)
+            | Message::Pong(_)
+            | Message::Reject(_) => Ok(()),
+            Message::Ping(nonce) => self.reply(Message::Pong(nonce), address),
+            Message::Announcement(text) => self.reply(Message::Announcement(text), address),
+            Message::Greet(point) => {
+                // The simulated peer has no user to ask, so it picks a message at random.
+                let (response, state) = MessageState::on_greeting(point, rand::random());
+                self.states.insert(address, state);
+                self.reply(Message::Response(response), address)
+            }
+            Message::Response(point) => match self.states.remove(&address) {
+                Some(state) => {
+                    let suite = Suite::P256Aes256Cbc;
+                    let (m0, m1, _) = state
+                        .on_response(suite, point)
+                        .map_err(|_| NetworkError::IncorrectMessage(address))?;
+                    self.reply(Message::Data(suite, m0, m1), address)
+                }
+                None => Err(NetworkError::IncorrectMessage(address)),
+            },
+            Message::Data(suite, m0, m1) | Message::SignedData(suite, m0, m1, _) => {
+                match self.states.remove(&address) {
+                    Some(state) => {
+                        let (message, _) = state
+                            .on_messages(suite, m0, m1)
+                            .map_err(|_| NetworkError::IncorrectMessage(address))?;
+                        let message: UserMessage = message.try_into().unwrap_or_default();
+                        let (point, state) =
+                            MessageState::send_message(message.clone(), message, None);
+                        self.states.insert(address, state);
+                        self.reply(Message::Greet(point), address)
+                    }
+                    None => Err(NetworkError::IncorrectMessage(address)),
+                }
+            }
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn local_addr(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(LOCAL_ADDRESS)
+    }
+
+    async fn send_to(&mut self, message: Message, address: SocketAddr) -> Result<(), NetworkError> {
+        info!("Sending message: {message:?} to loopback address: {address}");
+        self.on_message(message, address)
+    }
+
+    async fn broadcast(&mut self, message: Message) -> Result<(), NetworkError> {
+        self.send_to(message, ECHO_ADDRESS).await
+    }
+
+    async fn recv_from(&mut self) -> Result<(Message, SocketAddr), NetworkError> {
+        let (message, address) = self.receiver.recv().await.ok_or(NetworkError::TaskClosed)?;
+        info!("Received message: {message:?} from loopback address: {address}");
+        Ok((message, address))
+    }
+}
+
+/// Transport with the simulated echo peer next to a real one, so the setup can be tested without a
+/// second machine. Messages to [`ECHO_ADDRESS`] go to the echo peer, broadcasts reach both.
+#[derive(Debug)]
+pub(super) struct WithEcho<T>(T, Loopback);
+
+impl<T> WithEcho<T> {
+    /// Add the echo peer to the transport.
+    pub fn new(transport: T) -> Self {
+        Self(transport, Loopback::new())
+    }
+}
+
+impl<T: Transport> Transport for WithEcho<T> {
+    fn local_addr(&self) -> Result<SocketAddr, NetworkError> {
+        self.0.local_addr()
+    }
+
+    fn local_ips(&self) -> Result<Vec<IpAddr>, NetworkError> {
+        self.0.local_ips()
+    }
+
+    async fn send_to(&mut self, message: Message, address: SocketAddr) -> Result<(), NetworkError> {
+        if address == ECHO_ADDRESS {
+            self.1.send_to(message, address).await
+        } else {
+            self.0.send_to(message, address).await
+        }
+    }
+
+    async fn broadcast(&mut self, message: Message) -> Result<(), NetworkError> {
+        // The echo peer is announced first, it is reachable even when broadcasts are not.
+        self.1.broadcast(message.clone()).await?;
+        self.0.broadcast(message).await
+    }
+
+    async fn recv_from(&mut self) -> Result<(Message, SocketAddr), NetworkError> {
+        select! {
+            result = self.0.recv_from() => result,
+            result = self.1.recv_from() => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::OTMPSocket;
+    use super::*;
+
+    #[tokio::test]
+    async fn echo_answers_next_to_socket() {
+        let socket = OTMPSocket::bind_to(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+            .await
+            .unwrap();
+        let mut transport = WithEcho::new(socket);
+        let local = transport.local_addr().unwrap();
+
+        transport
+            .send_to(Message::Ping(7), ECHO_ADDRESS)
+            .await
+            .unwrap();
+        let (message, from) = transport.recv_from().await.unwrap();
+        assert_eq!((message, from), (Message::Pong(7), ECHO_ADDRESS));
+
+        // Other addresses still go through the socket, here back to itself.
+        transport.send_to(Message::Ping(8), local).await.unwrap();
+        let (message, from) = transport.recv_from().await.unwrap();
+        assert_eq!((message, from.port()), (Message::Ping(8), local.port()));
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::future::pending;
+use std::net::{IpAddr, SocketAddr};
+
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use rand::{thread_rng, Rng};
+use tracing::{debug, warn};
+
+use super::NetworkError;
+
+static SERVICE_TYPE: &str = "_otmp._udp.local."; // DNS-SD service type announced by every host
+
+/// Announcement of the host over multicast DNS and the search for the other hosts.
+pub(super) struct Mdns {
+    daemon: ServiceDaemon,
+    browse: Receiver<ServiceEvent>,
+    fullname: String,
+}
+
+impl Mdns {
+    /// Announce the host at the addresses and port, and start looking for the others.
+    /// The instance name is random, so the username is not given away.
+    pub fn start(ips: &[IpAddr], port: u16) -> Result<Self, NetworkError> {
+        let daemon = ServiceDaemon::new()?;
+        let instance = format!("otmp-{:016x}", thread_rng().gen::<u64>());
+        let host = format!("{instance}.local.");
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host,
+            ips,
+            port,
+            HashMap::<String, String>::new(),
+        )?;
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        let browse = daemon.browse(SERVICE_TYPE)?;
+        Ok(Self {
+            daemon,
+            browse,
+            fullname,
+        })
+    }
+
+    /// Wait for another host to be found. Returns the addresses it is reachable at, IPv4 first.
+    pub async fn next_host(&self) -> Vec<SocketAddr> {
+        loop {
+            match self.browse.recv_async().await {
+                Ok(ServiceEvent::ServiceResolved(info)) if info.get_fullname() != self.fullname => {
+                    let port = info.get_port();
+                    let mut addrs: Vec<SocketAddr> = info
+                        .get_addresses()
+                        .iter()
+                        .map(|ip| SocketAddr::new(*ip, port))
+                        .collect();
+                    addrs.sort_by_key(SocketAddr::is_ipv6);
+                    return addrs;
+                }
+                Ok(event) => debug!("mDNS event: {event:?}"),
+                // The daemon has stopped, nothing more will be found.
+                Err(_) => return pending().await,
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Mdns {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mdns")
+            .field("fullname", &self.fullname)
+            .finish()
+    }
+}
+
+impl Drop for Mdns {
+    fn drop(&mut self) {
+        if let Err(error) = self.daemon.unregister(&self.fullname) {
+            warn!("Failed to withdraw the mDNS announcement: {error}");
+        }
+        if let Err(error) = self.daemon.shutdown() {
+            warn!("Failed to stop the mDNS daemon: {error}");
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use std::io::{Error, ErrorKind};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
 
 use local_ip_address::local_ip;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
@@ -7,12 +7,21 @@ use p256::elliptic_curve::sec1::{EncodedPoint, FromEncodedPoint, ToEncodedPoint}
 use p256::{NistP256, ProjectivePoint as CurvePoint};
 use thiserror::Error;
 use tokio::net::UdpSocket;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-use super::{CryptoError, NetworkError, Username, UsernameError};
+use super::{
+    CryptoError, NetworkError, PublicKey, Signature, Suite, UserMessage, UserMessageError,
+    Username, UsernameError, WIRE_COMPRESSED,
+};
 
 static MAGIC_NUMBER: &[u8] = b"OTMP"; // Oblivious Transfer Message Protocol
-static HEADER_SIZE: usize = 7; // 4 - magic number, 1 - message type, 2 - message length
+static HEADER_SIZE: usize = 9; // 4 - magic number, 1 - message type, 4 - message length (big-endian)
+static MAX_MESSAGE_SIZE: usize = 1 << 20; // Upper bound for the declared payload length
+static SIGNATURE_SIZE: usize = 64; // Ed25519 signature prepended to signed data
+static POINT_SIZE: usize = 33; // Compressed curve point prepended to an encrypted name
+static DIRECTED_GREET: u8 = 14; // Type byte of a greeting sent to a single host instead of broadcast
+static BIND_ATTEMPTS: u16 = 10; // Ports tried in a row when falling back from a busy one
+static MAX_DATAGRAM_SIZE: usize = 65507; // Largest UDP payload over IPv4, the frame limit of this protocol version
 
 /// Protocol message parse error.
 #[derive(Debug, Error)]
@@ -25,47 +34,120 @@ pub enum MessageError {
     InvalidMessageType,
     #[error("Message length is invalid")]
     InvalidMessageLength,
+    #[error("Message length {0} exceeds the maximum allowed size")]
+    MessageTooLarge(usize),
+    #[error("First data message length {0} does not fit in the length field")]
+    DataTooLong(usize),
+    #[error("Cipher suite {0} is unknown")]
+    UnknownSuite(u8),
+    #[error("Frame of {0} bytes does not fit in a single datagram")]
+    DatagramTooLarge(usize),
+    #[error("Sent only {0} of {1} bytes in a single datagram")]
+    PartialSend(usize, usize),
     #[error("Message is invalid UTF-8: {0}")]
     InvalidUtf8(#[from] std::string::FromUtf8Error),
     #[error("Greeting name is invalid: {0}")]
     InvalidUsername(#[from] UsernameError),
     #[error("Crypto error: {0}")]
     InvalidCrypto(#[from] CryptoError),
+    #[error("Announcement is invalid: {0}")]
+    InvalidAnnouncement(#[from] UserMessageError),
+}
+
+/// Reason a peer dropped a greeting, sent back in [`Message::Reject`].
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+pub enum RejectReason {
+    #[error("the name is not a valid username")]
+    InvalidName,
+    #[error("the host is busy with other transfers")]
+    Busy,
+    #[error("unknown reason {0}")]
+    Unknown(u8),
+}
+
+impl From<RejectReason> for u8 {
+    fn from(value: RejectReason) -> Self {
+        match value {
+            RejectReason::InvalidName => 0,
+            RejectReason::Busy => 1,
+            RejectReason::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u8> for RejectReason {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => RejectReason::InvalidName,
+            1 => RejectReason::Busy,
+            code => RejectReason::Unknown(code),
+        }
+    }
 }
 
 /// Protocol messages.
-#[derive(Debug)]
+///
+/// The hidden variants announce a host without its name. The name is sent only directly to a peer,
+/// encrypted with a key agreed from the exchanged points. A ping is answered with a pong carrying
+/// the same nonce, to measure the round-trip time. A reject tells the sender of a greeting why it
+/// was dropped. Greetings list the cipher suites the host supports and data names the one it was
+/// sent with. An announcement is plaintext broadcast to every peer, outside of any transfer.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Message {
-    BroadcastGreet(Username),
-    BroadcastResponse(Username),
+    BroadcastGreet(Username, Option<PublicKey>, Vec<Suite>),
+    BroadcastResponse(Username, Option<PublicKey>, Vec<Suite>),
     BroadcastBye,
     Greet(CurvePoint),
     Response(CurvePoint),
-    Data(Vec<u8>, Vec<u8>),
+    Data(Suite, Vec<u8>, Vec<u8>),
+    SignedData(Suite, Vec<u8>, Vec<u8>, Signature),
+    HiddenGreet(CurvePoint),
+    HiddenResponse(CurvePoint, Vec<u8>),
+    HiddenReveal(CurvePoint, Vec<u8>),
+    Ping(u64),
+    Pong(u64),
+    Reject(RejectReason),
+    Announcement(UserMessage),
 }
 
 impl Message {
-    /// Convert a message to bytes.
-    pub fn into_bytes(self) -> Vec<u8> {
-        self.into()
+    /// Convert a message to bytes. Fails if a length does not fit in its field.
+    pub fn try_into_bytes(self) -> Result<Vec<u8>, MessageError> {
+        self.try_into()
+    }
+
+    /// Convert a message sent to a single host to bytes. A greeting is marked as directed, so the
+    /// receiver can reject an invalid name without answering a broadcast.
+    pub fn try_into_directed_bytes(self) -> Result<Vec<u8>, MessageError> {
+        let mut bytes = self.try_into_bytes()?;
+        if bytes[4] == 0 {
+            bytes[4] = DIRECTED_GREET;
+        }
+        Ok(bytes)
     }
 }
 
-fn buffer(type_byte: u8, data: &[u8]) -> Vec<u8> {
+fn buffer(type_byte: u8, data: &[u8]) -> Result<Vec<u8>, MessageError> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(MessageError::MessageTooLarge(data.len()));
+    }
+
     let mut buffer = Vec::with_capacity(HEADER_SIZE + data.len());
     buffer.extend_from_slice(MAGIC_NUMBER);
     buffer.push(type_byte);
-    buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
     buffer.extend_from_slice(data);
-    buffer
+    Ok(buffer)
 }
 
-fn point_to_bytes(point: CurvePoint) -> Vec<u8> {
-    let encoded = point.to_encoded_point(true);
+/// Encode a point for the wire: SEC1 compressed, 33 bytes.
+pub fn point_to_bytes(point: CurvePoint) -> Vec<u8> {
+    let encoded = point.to_encoded_point(WIRE_COMPRESSED);
     encoded.as_bytes().to_vec()
 }
 
-fn bytes_to_point(bytes: &[u8]) -> Result<CurvePoint, CryptoError> {
+/// Decode a SEC1 point, compressed or not, rejecting bytes that are not on the curve.
+pub fn bytes_to_point(bytes: &[u8]) -> Result<CurvePoint, CryptoError> {
     let encoded =
         EncodedPoint::<NistP256>::from_bytes(bytes).map_err(|_| CryptoError::InvalidPoint)?;
     let option = CurvePoint::from_encoded_point(&encoded);
@@ -76,21 +158,140 @@ fn bytes_to_point(bytes: &[u8]) -> Result<CurvePoint, CryptoError> {
     }
 }
 
-impl From<Message> for Vec<u8> {
-    fn from(value: Message) -> Self {
+/// Encode a name with the supported suites and an optional signing key, as sent in greetings.
+/// The name is prefixed by its length in bytes as a big-endian `u16`, as a name limited in
+/// characters can take up to four bytes for each. The suites are prefixed by their count, the key
+/// takes the rest.
+pub(super) fn greet_to_bytes(
+    username: &Username,
+    key: Option<PublicKey>,
+    suites: &[Suite],
+) -> Vec<u8> {
+    let mut buf =
+        Vec::with_capacity(3 + username.len() + suites.len() + key.map_or(0, |key| key.len()));
+    // A username of at most `MAX_USERNAME_LENGTH` characters always fits.
+    buf.extend_from_slice(&(username.len() as u16).to_be_bytes());
+    buf.extend_from_slice(username.as_bytes());
+    buf.push(suites.len() as u8);
+    buf.extend(suites.iter().map(|&suite| u8::from(suite)));
+    if let Some(key) = key {
+        buf.extend_from_slice(&key);
+    }
+    buf
+}
+
+/// Decode a name with the supported suites and an optional signing key, as sent in greetings.
+/// Suites unknown to this host are skipped, a newer peer may list them.
+pub(super) fn bytes_to_greet(bytes: &[u8]) -> Result<Greeting, MessageError> {
+    if bytes.len() < 2 {
+        return Err(MessageError::InvalidMessageLength);
+    }
+
+    let len = usize::from(u16::from_be_bytes([bytes[0], bytes[1]]));
+    if len > bytes.len() - 2 {
+        return Err(MessageError::InvalidMessageLength);
+    }
+
+    let name = String::from_utf8(bytes[2..2 + len].to_vec())?;
+    let rest = &bytes[2 + len..];
+    let count = *rest.first().ok_or(MessageError::InvalidMessageLength)? as usize;
+    if count > rest.len() - 1 {
+        return Err(MessageError::InvalidMessageLength);
+    }
+
+    let suites = rest[1..1 + count]
+        .iter()
+        .filter_map(|&suite| Suite::try_from(suite).ok())
+        .collect();
+    let key = match &rest[1 + count..] {
+        [] => None,
+        key => Some(
+            key.try_into()
+                .map_err(|_| MessageError::InvalidMessageLength)?,
+        ),
+    };
+    Ok((Username::new(name)?, key, suites))
+}
+
+/// Name, signing key and suites decoded from a greeting.
+pub(super) type Greeting = (Username, Option<PublicKey>, Vec<Suite>);
+
+fn hidden_to_bytes(point: CurvePoint, name: &[u8]) -> Vec<u8> {
+    let mut buf = point_to_bytes(point);
+    buf.extend_from_slice(name);
+    buf
+}
+
+fn bytes_to_hidden(bytes: &[u8]) -> Result<(CurvePoint, Vec<u8>), MessageError> {
+    if bytes.len() < POINT_SIZE {
+        return Err(MessageError::InvalidMessageLength);
+    }
+
+    let point = bytes_to_point(&bytes[..POINT_SIZE])?;
+    Ok((point, bytes[POINT_SIZE..].to_vec()))
+}
+
+fn bytes_to_nonce(bytes: &[u8]) -> Result<u64, MessageError> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| MessageError::InvalidMessageLength)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Encode a message pair: the suite, the length of the first message as a big-endian `u16`, then
+/// both messages. The second one takes the rest of the frame.
+fn data_to_bytes(suite: Suite, m0: &[u8], m1: &[u8]) -> Result<Vec<u8>, MessageError> {
+    let len = u16::try_from(m0.len()).map_err(|_| MessageError::DataTooLong(m0.len()))?;
+    let mut buf = Vec::with_capacity(3 + m0.len() + m1.len());
+    buf.push(suite.into());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(m0);
+    buf.extend_from_slice(m1);
+    Ok(buf)
+}
+
+fn bytes_to_data(bytes: &[u8]) -> Result<(Suite, Vec<u8>, Vec<u8>), MessageError> {
+    if bytes.len() < 3 {
+        return Err(MessageError::InvalidMessageLength);
+    }
+
+    let suite = Suite::try_from(bytes[0])?;
+    let len = usize::from(u16::from_be_bytes([bytes[1], bytes[2]]));
+
+    if len > bytes.len() - 3 {
+        return Err(MessageError::InvalidMessageLength);
+    }
+
+    Ok((suite, bytes[3..3 + len].to_vec(), bytes[3 + len..].to_vec()))
+}
+
+impl TryFrom<Message> for Vec<u8> {
+    type Error = MessageError;
+
+    fn try_from(value: Message) -> Result<Self, Self::Error> {
         match value {
-            Message::BroadcastGreet(username) => buffer(0, username.as_bytes()),
-            Message::BroadcastResponse(username) => buffer(1, username.as_bytes()),
+            Message::BroadcastGreet(username, key, suites) => {
+                buffer(0, &greet_to_bytes(&username, key, &suites))
+            }
+            Message::BroadcastResponse(username, key, suites) => {
+                buffer(1, &greet_to_bytes(&username, key, &suites))
+            }
             Message::BroadcastBye => buffer(2, &[]),
             Message::Greet(point) => buffer(3, &point_to_bytes(point)),
             Message::Response(point) => buffer(4, &point_to_bytes(point)),
-            Message::Data(m0, m1) => {
-                let mut buf = Vec::with_capacity(2 + m0.len() + m1.len());
-                buf.extend_from_slice(&(m0.len() as u16).to_be_bytes());
-                buf.extend_from_slice(&m0);
-                buf.extend_from_slice(&m1);
-                buffer(5, &buf)
+            Message::Data(suite, m0, m1) => buffer(5, &data_to_bytes(suite, &m0, &m1)?),
+            Message::SignedData(suite, m0, m1, signature) => {
+                let mut buf = signature.to_vec();
+                buf.extend_from_slice(&data_to_bytes(suite, &m0, &m1)?);
+                buffer(6, &buf)
             }
+            Message::HiddenGreet(point) => buffer(7, &point_to_bytes(point)),
+            Message::HiddenResponse(point, name) => buffer(8, &hidden_to_bytes(point, &name)),
+            Message::HiddenReveal(point, name) => buffer(9, &hidden_to_bytes(point, &name)),
+            Message::Ping(nonce) => buffer(10, &nonce.to_be_bytes()),
+            Message::Pong(nonce) => buffer(11, &nonce.to_be_bytes()),
+            Message::Reject(reason) => buffer(12, &[reason.into()]),
+            Message::Announcement(text) => buffer(13, text.as_bytes()),
         }
     }
 }
@@ -107,20 +308,30 @@ impl TryFrom<&[u8]> for Message {
             return Err(MessageError::InvalidMagicNumber);
         }
 
-        let size = usize::from_be_bytes([0, 0, 0, 0, 0, 0, value[5], value[6]]);
+        let size = u32::from_be_bytes([value[5], value[6], value[7], value[8]]) as usize;
+
+        if size > MAX_MESSAGE_SIZE {
+            return Err(MessageError::MessageTooLarge(size));
+        }
 
         if value.len() != HEADER_SIZE + size {
             return Err(MessageError::InvalidMessageLength);
         }
 
-        match value[4] {
+        // A directed greeting only differs from a broadcast one in how an invalid name is handled.
+        let message_type = if value[4] == DIRECTED_GREET {
+            0
+        } else {
+            value[4]
+        };
+        match message_type {
             0 => {
-                let name = String::from_utf8(value[HEADER_SIZE..].to_vec())?;
-                Ok(Message::BroadcastGreet(Username::new(name)?))
+                let (name, key, suites) = bytes_to_greet(&value[HEADER_SIZE..])?;
+                Ok(Message::BroadcastGreet(name, key, suites))
             }
             1 => {
-                let name = String::from_utf8(value[HEADER_SIZE..].to_vec())?;
-                Ok(Message::BroadcastResponse(Username::new(name)?))
+                let (name, key, suites) = bytes_to_greet(&value[HEADER_SIZE..])?;
+                Ok(Message::BroadcastResponse(name, key, suites))
             }
             2 => match size {
                 0 => Ok(Message::BroadcastBye),
@@ -129,75 +340,195 @@ impl TryFrom<&[u8]> for Message {
             3 => Ok(Message::Greet(bytes_to_point(&value[HEADER_SIZE..])?)),
             4 => Ok(Message::Response(bytes_to_point(&value[HEADER_SIZE..])?)),
             5 => {
-                let mut len = [0; 8];
-                len[6] = value[HEADER_SIZE];
-                len[7] = value[HEADER_SIZE + 1];
-                let len = usize::from_be_bytes(len);
-
-                if len > size - 2 {
+                let (suite, m0, m1) = bytes_to_data(&value[HEADER_SIZE..])?;
+                Ok(Message::Data(suite, m0, m1))
+            }
+            6 => {
+                if size < SIGNATURE_SIZE {
                     return Err(MessageError::InvalidMessageLength);
                 }
 
-                let m0 = value[HEADER_SIZE + 2..HEADER_SIZE + 2 + len].to_vec();
-                let m1 = value[HEADER_SIZE + 2 + len..].to_vec();
-                Ok(Message::Data(m0, m1))
+                let signature = value[HEADER_SIZE..HEADER_SIZE + SIGNATURE_SIZE]
+                    .try_into()
+                    .map_err(|_| MessageError::InvalidMessageLength)?;
+                let (suite, m0, m1) = bytes_to_data(&value[HEADER_SIZE + SIGNATURE_SIZE..])?;
+                Ok(Message::SignedData(suite, m0, m1, signature))
+            }
+            7 => Ok(Message::HiddenGreet(bytes_to_point(&value[HEADER_SIZE..])?)),
+            8 => {
+                let (point, name) = bytes_to_hidden(&value[HEADER_SIZE..])?;
+                Ok(Message::HiddenResponse(point, name))
+            }
+            9 => {
+                let (point, name) = bytes_to_hidden(&value[HEADER_SIZE..])?;
+                Ok(Message::HiddenReveal(point, name))
+            }
+            10 => Ok(Message::Ping(bytes_to_nonce(&value[HEADER_SIZE..])?)),
+            11 => Ok(Message::Pong(bytes_to_nonce(&value[HEADER_SIZE..])?)),
+            12 => match value[HEADER_SIZE..] {
+                [code] => Ok(Message::Reject(code.into())),
+                _ => Err(MessageError::InvalidMessageLength),
+            },
+            13 => {
+                let text = String::from_utf8(value[HEADER_SIZE..].to_vec())?;
+                Ok(Message::Announcement(UserMessage::try_from(text)?))
             }
             _ => Err(MessageError::InvalidMessageType),
         }
     }
 }
 
+/// Transport used by the network task to exchange protocol messages.
+pub(super) trait Transport {
+    /// Get the address the transport is reachable at.
+    fn local_addr(&self) -> Result<SocketAddr, NetworkError>;
+
+    /// Get every address the own messages of the host may arrive from, to drop its broadcasts.
+    fn local_ips(&self) -> Result<Vec<IpAddr>, NetworkError> {
+        Ok(vec![self.local_addr()?.ip()])
+    }
+
+    /// Send a message to a specific address.
+    /// A stream transport writes the remainder until the whole message is sent, a datagram
+    /// transport fails with [`MessageError::PartialSend`] as the rest cannot follow separately.
+    async fn send_to(&mut self, message: Message, address: SocketAddr) -> Result<(), NetworkError>;
+
+    /// Broadcast a message.
+    async fn broadcast(&mut self, message: Message) -> Result<(), NetworkError>;
+
+    /// Receive a message with the sender address.
+    async fn recv_from(&mut self) -> Result<(Message, SocketAddr), NetworkError>;
+}
+
 /// Oblivious Transfer Message Protocol socket.
 #[derive(Debug)]
-pub(super) struct OTMPSocket(UdpSocket, u16);
+pub(super) struct OTMPSocket(UdpSocket, Vec<u8>);
 
 impl OTMPSocket {
-    /// Bind to a port.
-    /// The Socket is set to broadcast mode.
-    pub async fn bind(port: u16) -> Result<Self, std::io::Error> {
-        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-        let socket = UdpSocket::bind(address).await?;
+    /// Bind to a port on the address, `0.0.0.0` listening on all interfaces.
+    /// The Socket is set to broadcast mode and allocates a receive buffer fitting the largest
+    /// datagram.
+    /// Broadcasts go to the network of the interface the address belongs to.
+    pub async fn bind_to(ip: IpAddr, port: u16) -> Result<Self, std::io::Error> {
+        let socket = UdpSocket::bind(SocketAddr::new(ip, port)).await?;
         socket.set_broadcast(true)?;
-        Ok(Self(socket, port))
+        Ok(Self(socket, vec![0; MAX_DATAGRAM_SIZE]))
     }
 
-    /// Send a message to a specific address.
-    pub async fn send_to(&self, message: Message, address: SocketAddr) -> Result<(), Error> {
-        info!("Sending message: {message:?} to address: {address}");
-        let bytes = message.into_bytes();
-        let size = self.0.send_to(&bytes, address).await?;
-        if size != bytes.len() {
-            warn!("Failed to send all bytes to address: {address}");
-            return Err(Error::new(ErrorKind::Other, "Failed to send all bytes"));
+    /// Bind to the port on the address, or to one of the next few ports while they are in use.
+    pub async fn bind_from(ip: IpAddr, port: u16) -> Result<Self, std::io::Error> {
+        let mut result = Self::bind_to(ip, port).await;
+        for next in (1..BIND_ATTEMPTS).filter_map(|offset| port.checked_add(offset)) {
+            match result {
+                Err(error) if error.kind() == ErrorKind::AddrInUse => {
+                    debug!("Port {} is in use, trying {next}", next - 1);
+                    result = Self::bind_to(ip, next).await;
+                }
+                _ => break,
+            }
         }
-        Ok(())
+        result
     }
+}
 
-    /// Broadcast a message.
-    pub async fn broadcast(&self, message: Message) -> Result<(), NetworkError> {
-        self.send_to(message, get_broadcast(self.1)?).await?;
-        Ok(())
+impl Transport for OTMPSocket {
+    /// Get the local address of the socket.
+    /// If the socket is bound to an unspecified address, the local IP address is used instead.
+    fn local_addr(&self) -> Result<SocketAddr, NetworkError> {
+        let mut address = self.0.local_addr()?;
+        if address.ip().is_unspecified() {
+            address.set_ip(local_ip()?);
+        }
+        Ok(address)
+    }
+
+    /// Bound to all interfaces, an own broadcast arrives from the address of the interface that
+    /// sent it, which need not be the local IP address of a multi-homed host.
+    fn local_ips(&self) -> Result<Vec<IpAddr>, NetworkError> {
+        let bound = self.0.local_addr()?.ip();
+        if !bound.is_unspecified() {
+            return Ok(vec![bound]);
+        }
+        let interfaces = NetworkInterface::show()?;
+        let addresses = interfaces.into_iter().flat_map(|interface| interface.addr);
+        Ok(addresses.map(|address| address.ip()).collect())
+    }
+
+    async fn send_to(&mut self, message: Message, address: SocketAddr) -> Result<(), NetworkError> {
+        info!("Sending message: {message:?} to address: {address}");
+        let bytes = message
+            .try_into_directed_bytes()
+            .map_err(NetworkError::EncodeError)?;
+        self.send_bytes(&bytes, address).await
+    }
+
+    async fn broadcast(&mut self, message: Message) -> Result<(), NetworkError> {
+        let address = get_broadcast(self.local_addr()?)?;
+        info!("Broadcasting message: {message:?} to address: {address}");
+        let bytes = message
+            .try_into_bytes()
+            .map_err(NetworkError::EncodeError)?;
+        self.send_bytes(&bytes, address).await
     }
 
     /// Receive a message with the sender address.
-    pub async fn recv_from(&self) -> Result<(Message, SocketAddr), NetworkError> {
-        let mut buffer = [0; 2048];
-        let (size, address) = self.0.recv_from(&mut buffer).await?;
-        let message = Message::try_from(&buffer[..size])?;
-        info!("Received message: {message:?} from address: {address}");
-        Ok((message, address))
+    /// A directed greeting with an invalid name fails with [`NetworkError::InvalidName`], so it
+    /// can be rejected. Other messages with an invalid name, which may have been broadcast, are
+    /// dropped.
+    async fn recv_from(&mut self) -> Result<(Message, SocketAddr), NetworkError> {
+        loop {
+            let (size, address) = self.0.recv_from(&mut self.1).await?;
+            let directed = self.1.get(4) == Some(&DIRECTED_GREET);
+            let message = match Message::try_from(&self.1[..size]) {
+                Ok(message) => message,
+                Err(error @ (MessageError::InvalidUsername(_) | MessageError::InvalidUtf8(_))) => {
+                    if directed {
+                        return Err(NetworkError::InvalidName(address, error));
+                    }
+                    debug!("Dropping message with an invalid name from {address}: {error}");
+                    continue;
+                }
+                Err(error) => return Err(NetworkError::MessageError(error)),
+            };
+            info!("Received message: {message:?} from address: {address}");
+            return Ok((message, address));
+        }
     }
 }
 
-fn get_broadcast(port: u16) -> Result<SocketAddr, NetworkError> {
-    let local_address = local_ip()?;
+impl OTMPSocket {
+    /// Send a frame in a single datagram. Frames are not fragmented, so one over the datagram
+    /// limit is refused.
+    async fn send_bytes(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), NetworkError> {
+        if bytes.len() > MAX_DATAGRAM_SIZE {
+            let error = MessageError::DatagramTooLarge(bytes.len());
+            return Err(NetworkError::EncodeError(error));
+        }
+        let size = self.0.send_to(bytes, address).await?;
+        check_sent(size, bytes.len()).map_err(|error| {
+            warn!("Failed to send all bytes to address: {address}");
+            NetworkError::SendError(error)
+        })
+    }
+}
+
+/// Check that a datagram was sent whole.
+fn check_sent(sent: usize, total: usize) -> Result<(), MessageError> {
+    if sent == total {
+        Ok(())
+    } else {
+        Err(MessageError::PartialSend(sent, total))
+    }
+}
 
+/// Get the broadcast address of the interface the local address belongs to.
+fn get_broadcast(local: SocketAddr) -> Result<SocketAddr, NetworkError> {
     for interface in NetworkInterface::show()? {
         for address in interface.addr {
-            if address.ip() == local_address {
+            if address.ip() == local.ip() {
                 return address
                     .broadcast()
-                    .map(|addr| SocketAddr::new(addr, port))
+                    .map(|addr| SocketAddr::new(addr, local.port()))
                     .ok_or(NetworkError::BroadcastAddressNotFound);
             }
         }
@@ -205,3 +536,447 @@ fn get_broadcast(port: u16) -> Result<SocketAddr, NetworkError> {
 
     Err(NetworkError::BroadcastAddressNotFound)
 }
+
+#[cfg(test)]
+mod tests {
+    use p256::elliptic_curve::Field;
+    use p256::Scalar;
+    use rand::{thread_rng, RngCore};
+
+    use super::super::{MAX_MESSAGE_LENGTH, MAX_USERNAME_LENGTH};
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_falls_back_from_port_in_use() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let busy = std::net::UdpSocket::bind((ip, 0)).unwrap();
+        let port = busy.local_addr().unwrap().port();
+
+        let error = OTMPSocket::bind_to(ip, port).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+        let socket = OTMPSocket::bind_from(ip, port).await.unwrap();
+        let bound = socket.0.local_addr().unwrap().port();
+        assert!(bound > port && bound < port.saturating_add(BIND_ATTEMPTS));
+    }
+
+    fn round_trip(message: Message) {
+        let bytes = message.clone().try_into_bytes().unwrap();
+        assert_eq!(Message::try_from(bytes.as_slice()).unwrap(), message);
+    }
+
+    fn frame(type_byte: u8, data: &[u8]) -> Vec<u8> {
+        buffer(type_byte, data).unwrap()
+    }
+
+    fn username() -> Username {
+        Username::new("alice".to_string()).unwrap()
+    }
+
+    fn point() -> CurvePoint {
+        CurvePoint::GENERATOR * Scalar::random(thread_rng())
+    }
+
+    static SUITE: Suite = Suite::P256Aes256Cbc;
+
+    fn suites() -> Vec<Suite> {
+        vec![SUITE]
+    }
+
+    #[test]
+    fn round_trip_all_variants() {
+        round_trip(Message::BroadcastGreet(username(), None, suites()));
+        round_trip(Message::BroadcastGreet(username(), Some([7; 32]), suites()));
+        round_trip(Message::BroadcastResponse(username(), None, Vec::new()));
+        round_trip(Message::BroadcastResponse(
+            username(),
+            Some([9; 32]),
+            suites(),
+        ));
+        round_trip(Message::BroadcastBye);
+        round_trip(Message::Greet(point()));
+        round_trip(Message::Response(point()));
+        round_trip(Message::Data(SUITE, b"first".to_vec(), b"second".to_vec()));
+        round_trip(Message::Data(SUITE, Vec::new(), Vec::new()));
+        round_trip(Message::SignedData(
+            SUITE,
+            b"a".to_vec(),
+            b"b".to_vec(),
+            [3; 64],
+        ));
+        round_trip(Message::HiddenGreet(point()));
+        round_trip(Message::HiddenResponse(point(), b"name".to_vec()));
+        round_trip(Message::HiddenReveal(point(), Vec::new()));
+        round_trip(Message::Ping(0));
+        round_trip(Message::Pong(u64::MAX));
+        round_trip(Message::Reject(RejectReason::InvalidName));
+        round_trip(Message::Reject(RejectReason::Busy));
+        round_trip(Message::Reject(RejectReason::Unknown(7)));
+        let text = UserMessage::try_from("hello everyone".to_string()).unwrap();
+        round_trip(Message::Announcement(text));
+    }
+
+    #[test]
+    fn random_payloads_do_not_panic() {
+        let mut rng = thread_rng();
+        for type_byte in 0..=13 {
+            for len in 0..100 {
+                let mut data = vec![0; len];
+                rng.fill_bytes(&mut data);
+                let _ = Message::try_from(frame(type_byte, &data).as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn reject_is_smaller_than_greetings() {
+        let reject = frame(12, &[0]);
+        let name = Username::new("a".to_string()).unwrap();
+        let greet = Message::BroadcastGreet(name, None, Vec::new());
+
+        assert!(reject.len() < greet.try_into_bytes().unwrap().len());
+        assert!(matches!(
+            Message::try_from(frame(12, &[]).as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_name_reports_sender() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut socket = OTMPSocket::bind_to(ip, 0).await.unwrap();
+        let sender = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+        let address = socket.0.local_addr().unwrap();
+
+        sender
+            .send_to(&frame(DIRECTED_GREET, &[0, 1, 0xFF]), address)
+            .await
+            .unwrap();
+        assert!(matches!(
+            socket.recv_from().await,
+            Err(NetworkError::InvalidName(from, _)) if from == sender.local_addr().unwrap()
+        ));
+    }
+
+    #[test]
+    fn directed_greet_round_trip() {
+        let greet = Message::BroadcastGreet(username(), Some([7; 32]), suites());
+        let bytes = greet.clone().try_into_directed_bytes().unwrap();
+
+        assert_eq!(bytes[4], DIRECTED_GREET);
+        assert_eq!(Message::try_from(bytes.as_slice()).unwrap(), greet);
+    }
+
+    #[tokio::test]
+    async fn invalid_name_in_broadcast_greet_is_dropped() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut socket = OTMPSocket::bind_to(ip, 0).await.unwrap();
+        let sender = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+        let address = socket.0.local_addr().unwrap();
+
+        for bytes in [frame(0, &[0, 1, 0xFF]), frame(10, &7u64.to_be_bytes())] {
+            sender.send_to(&bytes, address).await.unwrap();
+        }
+        assert!(matches!(
+            socket.recv_from().await,
+            Ok((Message::Ping(_), _))
+        ));
+    }
+
+    #[test]
+    fn ping_with_wrong_nonce_size() {
+        for len in [0, 7, 9] {
+            assert!(matches!(
+                Message::try_from(frame(10, &vec![1; len]).as_slice()),
+                Err(MessageError::InvalidMessageLength)
+            ));
+        }
+    }
+
+    #[test]
+    fn too_long_announcement() {
+        let text = "a".repeat(MAX_MESSAGE_LENGTH + 1);
+        let bytes = buffer(13, text.as_bytes()).unwrap();
+        assert!(matches!(
+            Message::try_from(bytes.as_slice()),
+            Err(MessageError::InvalidAnnouncement(_))
+        ));
+    }
+
+    #[test]
+    fn point_round_trip() {
+        for point in [CurvePoint::GENERATOR, point(), point()] {
+            let bytes = point_to_bytes(point);
+            assert_eq!(bytes.len(), POINT_SIZE);
+            assert_eq!(bytes_to_point(&bytes).unwrap(), point);
+        }
+    }
+
+    #[test]
+    fn partial_send() {
+        assert!(check_sent(10, 10).is_ok());
+        assert!(matches!(
+            check_sent(4, 10),
+            Err(MessageError::PartialSend(4, 10))
+        ));
+    }
+
+    #[test]
+    fn hidden_name_too_short() {
+        assert!(matches!(
+            Message::try_from(frame(8, &vec![2; POINT_SIZE - 1]).as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let bytes = frame(2, &[]);
+        for len in 0..HEADER_SIZE {
+            assert!(matches!(
+                Message::try_from(&bytes[..len]),
+                Err(MessageError::MissingHeaderBytes)
+            ));
+        }
+    }
+
+    #[test]
+    fn wrong_magic_number() {
+        let mut bytes = frame(2, &[]);
+        bytes[0] = b'X';
+        assert!(matches!(
+            Message::try_from(bytes.as_slice()),
+            Err(MessageError::InvalidMagicNumber)
+        ));
+    }
+
+    #[test]
+    fn invalid_message_type() {
+        assert!(matches!(
+            Message::try_from(frame(42, &[]).as_slice()),
+            Err(MessageError::InvalidMessageType)
+        ));
+    }
+
+    #[test]
+    fn declared_length_mismatch() {
+        let mut bytes = frame(2, &[]);
+        bytes.push(0);
+        assert!(matches!(
+            Message::try_from(bytes.as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+
+        let bytes = frame(5, &[0, 0, 1, 2, 3]);
+        assert!(matches!(
+            Message::try_from(&bytes[..bytes.len() - 1]),
+            Err(MessageError::InvalidMessageLength)
+        ));
+    }
+
+    #[test]
+    fn bye_with_payload() {
+        assert!(matches!(
+            Message::try_from(frame(2, &[0]).as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+    }
+
+    #[test]
+    fn data_without_length_prefix() {
+        for data in [&[][..], &[0], &[0, 0]] {
+            assert!(matches!(
+                Message::try_from(frame(5, data).as_slice()),
+                Err(MessageError::InvalidMessageLength)
+            ));
+        }
+
+        let signature = vec![3; SIGNATURE_SIZE + 1];
+        for len in [SIGNATURE_SIZE, SIGNATURE_SIZE + 1] {
+            assert!(matches!(
+                Message::try_from(frame(6, &signature[..len]).as_slice()),
+                Err(MessageError::InvalidMessageLength)
+            ));
+        }
+    }
+
+    #[test]
+    fn data_first_message_exceeds_frame() {
+        let bytes = frame(5, &[0, 0, 3, 1, 2]);
+        assert!(matches!(
+            Message::try_from(bytes.as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+    }
+
+    #[test]
+    fn data_first_message_fills_frame() {
+        let bytes = frame(5, &[0, 0, 2, 1, 2]);
+        assert_eq!(
+            Message::try_from(bytes.as_slice()).unwrap(),
+            Message::Data(SUITE, vec![1, 2], Vec::new())
+        );
+    }
+
+    #[test]
+    fn data_with_unknown_suite() {
+        for type_byte in [5, 6] {
+            let mut data = vec![0; if type_byte == 6 { SIGNATURE_SIZE } else { 0 }];
+            data.extend_from_slice(&[0xFF, 0, 0]);
+            assert!(matches!(
+                Message::try_from(frame(type_byte, &data).as_slice()),
+                Err(MessageError::UnknownSuite(0xFF))
+            ));
+        }
+    }
+
+    #[test]
+    fn greeting_skips_unknown_suites() {
+        let bytes = frame(0, &[0, 1, b'a', 3, 0xFE, 0, 0xFF]);
+        assert_eq!(
+            Message::try_from(bytes.as_slice()).unwrap(),
+            Message::BroadcastGreet(Username::new("a".to_string()).unwrap(), None, suites())
+        );
+    }
+
+    #[test]
+    fn signed_data_without_signature() {
+        assert!(matches!(
+            Message::try_from(frame(6, &[0; 10]).as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+    }
+
+    #[test]
+    fn invalid_points() {
+        assert!(matches!(
+            Message::try_from(frame(3, &[]).as_slice()),
+            Err(MessageError::InvalidCrypto(CryptoError::InvalidPoint))
+        ));
+        let mut bytes = [0xFF; 33];
+        bytes[0] = 2;
+        assert!(matches!(
+            Message::try_from(frame(4, &bytes).as_slice()),
+            Err(MessageError::InvalidCrypto(CryptoError::InvalidPoint))
+        ));
+    }
+
+    #[test]
+    fn invalid_greetings() {
+        for data in [&[][..], &[0], &[0, 5, b'a']] {
+            assert!(matches!(
+                Message::try_from(frame(0, data).as_slice()),
+                Err(MessageError::InvalidMessageLength)
+            ));
+        }
+        assert!(matches!(
+            Message::try_from(frame(1, &[0, 0, 0]).as_slice()),
+            Err(MessageError::InvalidUsername(UsernameError::Empty))
+        ));
+        assert!(matches!(
+            Message::try_from(frame(0, &[0, 1, 0xFF]).as_slice()),
+            Err(MessageError::InvalidUtf8(_))
+        ));
+        assert!(matches!(
+            Message::try_from(frame(0, &[0, 1, b'a', 0, 1, 2]).as_slice()),
+            Err(MessageError::InvalidMessageLength)
+        ));
+        for suites in [&[][..], &[2, 0]] {
+            let mut data = vec![0, 1, b'a'];
+            data.extend_from_slice(suites);
+            assert!(matches!(
+                Message::try_from(frame(0, &data).as_slice()),
+                Err(MessageError::InvalidMessageLength)
+            ));
+        }
+    }
+
+    #[test]
+    fn long_multi_byte_username_round_trip() {
+        let name = "名".repeat(MAX_USERNAME_LENGTH);
+        let username = Username::new(name).unwrap();
+        assert!(username.len() > u8::MAX as usize);
+
+        round_trip(Message::BroadcastGreet(username.clone(), None, suites()));
+        round_trip(Message::BroadcastResponse(
+            username,
+            Some([1; 32]),
+            suites(),
+        ));
+    }
+
+    #[test]
+    fn large_payload_round_trip() {
+        let m0 = vec![0xAA; 35 * 1024];
+        let m1 = vec![0x55; 35 * 1024];
+        let bytes = Message::Data(SUITE, m0.clone(), m1.clone())
+            .try_into_bytes()
+            .unwrap();
+        assert!(bytes.len() > u16::MAX as usize);
+
+        match Message::try_from(bytes.as_slice()) {
+            Ok(Message::Data(_, r0, r1)) => {
+                assert_eq!(r0, m0);
+                assert_eq!(r1, m1);
+            }
+            other => panic!("unexpected parse result: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_over_datagram_is_not_sent() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut socket = OTMPSocket::bind_to(ip, 0).await.unwrap();
+        let address = socket.0.local_addr().unwrap();
+        let message = Message::Data(SUITE, vec![0xAA; 35 * 1024], vec![0x55; 35 * 1024]);
+
+        assert!(matches!(
+            socket.send_to(message, address).await,
+            Err(NetworkError::EncodeError(MessageError::DatagramTooLarge(_)))
+        ));
+    }
+
+    #[test]
+    fn lengths_are_big_endian() {
+        let m0 = vec![1; u16::MAX as usize];
+        let bytes = Message::Data(SUITE, m0.clone(), vec![2])
+            .try_into_bytes()
+            .unwrap();
+        let size = (3 + m0.len() + 1) as u32;
+
+        assert_eq!(bytes[5..HEADER_SIZE], size.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 1..HEADER_SIZE + 3], [0xFF, 0xFF]);
+        assert_eq!(
+            Message::try_from(bytes.as_slice()).unwrap(),
+            Message::Data(SUITE, m0, vec![2])
+        );
+    }
+
+    #[test]
+    fn data_length_near_limit_round_trip() {
+        for len in [u16::MAX as usize - 1, u16::MAX as usize] {
+            round_trip(Message::Data(SUITE, vec![0x5A; len], b"rest".to_vec()));
+        }
+    }
+
+    #[test]
+    fn data_first_message_too_long() {
+        let m0 = vec![0; u16::MAX as usize + 1];
+
+        assert!(matches!(
+            Message::Data(SUITE, m0, Vec::new()).try_into_bytes(),
+            Err(MessageError::DataTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn declared_length_over_maximum() {
+        let mut bytes = MAGIC_NUMBER.to_vec();
+        bytes.push(5);
+        bytes.extend_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+
+        assert!(matches!(
+            Message::try_from(bytes.as_slice()),
+            Err(MessageError::MessageTooLarge(_))
+        ));
+    }
+}
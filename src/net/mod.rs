@@ -1,17 +1,28 @@
-use std::net::SocketAddr;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 use p256::Scalar;
 use thiserror::Error;
-use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
 
 pub use connection::*;
 use crypto::*;
+pub use crypto::{
+    decrypt, encrypt, into_key, self_test, CryptoError, MessageState, Suite, TranscriptHash,
+};
+pub use loopback::ECHO_ADDRESS;
+use loopback::*;
+use mdns::*;
 use message::*;
+pub use message::{bytes_to_point, point_to_bytes, MessageError, RejectReason};
 pub use peer::*;
 use task::*;
 
 mod connection;
 mod crypto;
+mod loopback;
+mod mdns;
 mod message;
 mod peer;
 mod task;
@@ -22,6 +33,8 @@ type Result<T> = std::result::Result<T, NetworkError>;
 pub enum NetworkError {
     #[error("Network task has ended")]
     TaskClosed,
+    #[error("Network task is busy, try again")]
+    ChannelFull,
     #[error("Network task has panicked")]
     TaskPanic,
     #[error("Failed to create socket: {0}. Terminating network task.")]
@@ -30,14 +43,59 @@ pub enum NetworkError {
     SocketError(#[from] std::io::Error),
     #[error("Received incorrect packet: {0}")]
     MessageError(#[from] MessageError),
+    #[error("Received a greeting with an invalid name from {0}: {1}")]
+    InvalidName(SocketAddr, MessageError),
+    #[error("Failed to encode packet: {0}")]
+    EncodeError(MessageError),
+    #[error("Failed to send packet: {0}")]
+    SendError(MessageError),
     #[error("Local IP address not found: {0}")]
     LocalIpNotFound(#[from] local_ip_address::Error),
     #[error("Error while accessing network interfaces: {0}")]
     InternetInterfaceError(#[from] network_interface::Error),
     #[error("Failed to retrieve local broadcast address")]
     BroadcastAddressNotFound,
+    #[error("Multicast DNS failed: {0}")]
+    MdnsError(#[from] mdns_sd::Error),
+    #[error("Failed to resolve host {0}: {1}")]
+    ResolveError(String, std::io::Error),
+    #[error("A message to {0} is still being transferred")]
+    SessionInFlight(SocketAddr),
+    #[error("Message to {0} was not answered in time")]
+    SessionTimeout(SocketAddr),
+    #[error("Message to {0} was cancelled")]
+    SessionCancelled(SocketAddr),
+    #[error("{0} is sending a message itself, try again after it")]
+    PeerBusy(SocketAddr),
+    #[error("Cannot send messages to own address {0}")]
+    SelfMessage(SocketAddr),
     #[error("Received incorrect message from {0}")]
     IncorrectMessage(SocketAddr),
+    #[error("Cryptography error with {0}: {1}")]
+    CryptoError(SocketAddr, CryptoError),
+}
+
+impl NetworkError {
+    /// Address of the peer the error is about, `None` if it is not about a single peer.
+    pub fn address(&self) -> Option<SocketAddr> {
+        match self {
+            Self::InvalidName(addr, _)
+            | Self::SessionInFlight(addr)
+            | Self::SessionTimeout(addr)
+            | Self::SessionCancelled(addr)
+            | Self::PeerBusy(addr)
+            | Self::SelfMessage(addr)
+            | Self::IncorrectMessage(addr)
+            | Self::CryptoError(addr, _) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a protocol frame, discarding the message. Entry point of the fuzz target.
+#[doc(hidden)]
+pub fn parse_frame(bytes: &[u8]) -> std::result::Result<(), MessageError> {
+    Message::try_from(bytes).map(drop)
 }
 
 impl From<SendError<Action>> for NetworkError {
@@ -46,19 +104,84 @@ impl From<SendError<Action>> for NetworkError {
     }
 }
 
+impl From<TrySendError<Action>> for NetworkError {
+    fn from(error: TrySendError<Action>) -> Self {
+        match error {
+            TrySendError::Full(_) => Self::ChannelFull,
+            TrySendError::Closed(_) => Self::TaskClosed,
+        }
+    }
+}
+
+/// Identifier of a message pair queued with [`NetworkHost::send`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MessageId(u64);
+
 /// Events received from socket.
 #[derive(Debug)]
 pub enum Event {
     Error(NetworkError),
+    LocalAddress(SocketAddr),
     Connected(Peer),
+    /// A known peer announced itself under a new name.
+    PeerRenamed(SocketAddr, Username),
     Disconnected(SocketAddr),
-    Message(SocketAddr, String),
+    /// Peers that did not answer a refresh were reported as disconnected.
+    Refreshed,
+    Message(SocketAddr, String, bool),
+    SendFailed(MessageId, NetworkError),
+    Delivered(MessageId),
+    Resolved(String, SocketAddr),
+    ResolveFailed(String, NetworkError),
+    /// A peer started a transfer; answer with [`NetworkHost::choose`] to receive one message.
+    ChoiceRequested(SocketAddr),
+    /// Broadcasts cannot be sent, so peers have to be added by address.
+    DiscoveryUnavailable(NetworkError),
+    /// Plaintext announcement broadcast by the peer to everyone.
+    Announcement(SocketAddr, String),
+    /// Message of the current session from the peer that could not be decrypted.
+    DecryptFailed(SocketAddr),
+    /// Transcript hash of a completed transfer with the peer, equal on both sides of a clean
+    /// session.
+    Transcript(SocketAddr, TranscriptHash),
+    /// Greeting and response points exchanged with the peer, compressed as sent, for analysis.
+    Points(SocketAddr, Vec<u8>, Vec<u8>),
+    /// Encrypted message pair of a transfer with the peer, as sent or received, for analysis.
+    Ciphertext(SocketAddr, Vec<u8>, Vec<u8>),
+    /// Secret scalar of a transfer with the peer as it starts, `a` when sending and `b` when
+    /// receiving. Only reported while [`NetworkHost::export_secrets`] is on.
+    Scalar(SocketAddr, Vec<u8>),
+    /// Short authentication string of a handshake with the peer, read aloud to detect a man in the
+    /// middle.
+    Sas(SocketAddr, String),
+    /// The peer dropped our greeting.
+    Rejected(SocketAddr, RejectReason),
+    /// Round-trip time of a ping sent with [`NetworkHost::ping`].
+    Pong(SocketAddr, Duration),
 }
 
 /// Actions user can perform.
 #[derive(Debug)]
 enum Action {
     Broadcast,
+    Heartbeat,
+    Greet(SocketAddr),
+    Resolve(String),
+    Choose(SocketAddr, bool),
+    DefaultChoice(Option<bool>),
+    Ping(SocketAddr),
+    SetName(Username),
+    CancelPeer(SocketAddr),
+    CancelSend(MessageId),
+    SetBlocklist(BTreeSet<IpAddr>, BTreeSet<Username>),
+    ExportSecrets(bool),
+    Announce(UserMessage),
     Disconnect,
-    Send(SocketAddr, UserMessage, UserMessage, Option<Scalar>),
+    Send(
+        MessageId,
+        SocketAddr,
+        UserMessage,
+        UserMessage,
+        Option<Scalar>,
+    ),
 }
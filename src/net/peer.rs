@@ -1,32 +1,116 @@
-use std::net::SocketAddr;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
 use std::thread::{spawn, JoinHandle};
+use std::time::Instant;
 
 use p256::Scalar;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tracing::error;
 
 use crate::UiContext as Context;
 
-use super::{Action, Event, NetworkError, NetworkTask, Result, UserMessage, Username};
+use super::{
+    Action, Event, MessageId, NetworkError, NetworkTask, Peer, PeerList, Result, UserMessage,
+    Username,
+};
 
 static CHANNEL_SIZE: usize = 100;
 
+/// Status of the network host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NetworkStatus {
+    Connecting,
+    Connected(SocketAddr),
+    Failed,
+}
+
+/// How the network host finds peers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum Discovery {
+    /// Broadcast greetings, so peers on the local network find each other.
+    #[default]
+    Broadcast,
+    /// Never broadcast, only greet peers added by address. For networks where broadcasts are
+    /// blocked or should not be sent.
+    Directed,
+    /// Announce the host over multicast DNS and greet the hosts found this way, nothing is
+    /// broadcast.
+    Mdns,
+}
+
+/// Options of the network host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NetworkOptions {
+    /// Leave the name out of broadcasts and only send it encrypted directly to peers that answer.
+    /// This hides it from passive listeners, not from hosts taking part.
+    pub hide_name: bool,
+    /// Follow a port in use by the next few, the one bound is reported by
+    /// [`Event::LocalAddress`]. Without it a port in use fails the task.
+    pub port_fallback: bool,
+    /// How peers are found.
+    pub discovery: Discovery,
+    /// Sessions kept with one peer at once, at least one. Messages are matched to sessions by
+    /// address, so further messages to the peer wait for the one in flight and a greeting from
+    /// the peer is rejected meanwhile.
+    pub max_sessions: usize,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self {
+            hide_name: false,
+            port_fallback: false,
+            discovery: Discovery::default(),
+            max_sessions: 1,
+        }
+    }
+}
+
 /// Peer to peer network implementation.
+///
+/// Actions are queued without blocking the caller. While the network task is behind and the queue
+/// is full they fail with [`NetworkError::ChannelFull`], to be retried later.
 #[derive(Debug)]
 pub struct NetworkHost {
     join_handle: JoinHandle<()>,
     receiver: Receiver<Event>,
     sender: Sender<Action>,
     name: Username,
+    peers: PeerList,
+    local_address: Option<SocketAddr>,
+    next_id: u64,
 }
 
 impl NetworkHost {
-    /// Create a new network host.
-    pub fn new(ctx: Context, name: Username, port: u16) -> Self {
+    /// Create a new network host bound to the address, `0.0.0.0` listening on all interfaces.
+    ///
+    /// With [`Discovery::Directed`] or [`Discovery::Mdns`] nothing is broadcast, refreshes,
+    /// heartbeats and announcements go to the known peers only.
+    pub fn new(ctx: Context, name: Username, bind: SocketAddr, options: NetworkOptions) -> Self {
+        Self::start(name, move |action, event, username, peers| {
+            NetworkTask::run(action, event, username, peers, ctx, bind, options)
+        })
+    }
+
+    /// Create a new network host backed by an in-process loopback peer instead of the network.
+    pub fn new_loopback(ctx: Context, name: Username, options: NetworkOptions) -> Self {
+        Self::start(name, move |action, event, username, peers| {
+            NetworkTask::run_loopback(action, event, username, peers, ctx, options)
+        })
+    }
+
+    fn start<F>(name: Username, task: F) -> Self
+    where
+        F: FnOnce(Receiver<Action>, Sender<Event>, Username, PeerList) + Send + 'static,
+    {
         let (sender, action) = channel(CHANNEL_SIZE);
         let (event, receiver) = channel(CHANNEL_SIZE);
         let username = name.clone();
-        let join_handle = spawn(move || NetworkTask::run(action, event, username, ctx, port));
+        let peers = PeerList::default();
+        let task_peers = peers.clone();
+        let join_handle = spawn(move || task(action, event, username, task_peers));
 
         if let Err(error) = sender.blocking_send(Action::Broadcast) {
             error!("Failed to send initial broadcast event: {}", error);
@@ -37,15 +121,98 @@ impl NetworkHost {
             receiver,
             sender,
             name,
+            peers,
+            local_address: None,
+            next_id: 0,
         }
     }
 
-    /// Broadcast message to receive peers.
+    /// Broadcast message to receive peers. Known peers that do not answer within a few seconds
+    /// are dropped, followed by [`Event::Refreshed`].
     pub fn refresh_hosts(&self) -> Result<()> {
-        Ok(self.sender.blocking_send(Action::Broadcast)?)
+        Ok(self.sender.try_send(Action::Broadcast)?)
+    }
+
+    /// Resolve a `host:port` name and greet the peer at the first address found.
+    /// The outcome is reported by [`Event::Resolved`] or [`Event::ResolveFailed`].
+    pub fn add_peer_by_name(&self, host: String) -> Result<()> {
+        Ok(self.sender.try_send(Action::Resolve(host))?)
+    }
+
+    /// Broadcast presence without forgetting the known peers, keeping them seen.
+    pub fn heartbeat(&self) -> Result<()> {
+        Ok(self.sender.try_send(Action::Heartbeat)?)
     }
 
-    /// Disconnect from network and clean up resources.
+    /// Greet a peer at a specific address to learn its name.
+    pub fn add_peer(&self, addr: SocketAddr) -> Result<()> {
+        Ok(self.sender.try_send(Action::Greet(addr))?)
+    }
+
+    /// Answer an [`Event::ChoiceRequested`], receiving the second message of the pair if `c` is
+    /// set.
+    pub fn choose(&self, addr: SocketAddr, c: bool) -> Result<()> {
+        Ok(self.sender.try_send(Action::Choose(addr, c))?)
+    }
+
+    /// Answer every transfer with the choice instead of asking through [`Event::ChoiceRequested`],
+    /// for scripted runs. `None` asks again.
+    pub fn set_default_choice(&self, c: Option<bool>) -> Result<()> {
+        Ok(self.sender.try_send(Action::DefaultChoice(c))?)
+    }
+
+    /// Broadcast plaintext to every peer, outside of any transfer. Peers report it by
+    /// [`Event::Announcement`].
+    pub fn announce(&self, text: UserMessage) -> Result<()> {
+        Ok(self.sender.try_send(Action::Announce(text))?)
+    }
+
+    /// Ping a peer, the round-trip time is reported by [`Event::Pong`].
+    pub fn ping(&self, addr: SocketAddr) -> Result<()> {
+        Ok(self.sender.try_send(Action::Ping(addr))?)
+    }
+
+    /// Drop the transfers in progress with a peer, such as when its chat is closed. A message still
+    /// being sent is reported by [`Event::SendFailed`].
+    pub fn cancel_peer(&self, addr: SocketAddr) -> Result<()> {
+        Ok(self.sender.try_send(Action::CancelPeer(addr))?)
+    }
+
+    /// Cancel a message pair the peer has not answered yet, reported by [`Event::SendFailed`].
+    /// Does nothing once the handshake completed, the pair is delivered then.
+    pub fn cancel_send(&self, id: MessageId) -> Result<()> {
+        Ok(self.sender.try_send(Action::CancelSend(id))?)
+    }
+
+    /// Drop everything received from the addresses and from peers with the names, before it
+    /// produces any event. Known peers that become blocked are reported by
+    /// [`Event::Disconnected`]. Replaces the previous blocklist.
+    pub fn set_blocklist(
+        &self,
+        addresses: BTreeSet<IpAddr>,
+        names: BTreeSet<Username>,
+    ) -> Result<()> {
+        Ok(self
+            .sender
+            .try_send(Action::SetBlocklist(addresses, names))?)
+    }
+
+    /// Report the secret scalar of each transfer by [`Event::Scalar`], so a failed handshake can
+    /// be reproduced. Off by default, as the scalars open the messages.
+    pub fn export_secrets(&self, export: bool) -> Result<()> {
+        Ok(self.sender.try_send(Action::ExportSecrets(export))?)
+    }
+
+    /// Change the announced name without reconnecting. Presence is announced again, so peers
+    /// report the host as renamed.
+    pub fn set_name(&mut self, name: Username) -> Result<()> {
+        self.sender.try_send(Action::SetName(name.clone()))?;
+        self.name = name;
+        Ok(())
+    }
+
+    /// Disconnect from network and clean up resources. Unlike the other actions this waits for room
+    /// in the queue, as it waits for the task to end anyway.
     pub fn disconnect(self) -> Result<()> {
         if !self.sender.is_closed() {
             self.sender.blocking_send(Action::Disconnect)?;
@@ -56,25 +223,126 @@ impl NetworkHost {
         Ok(())
     }
 
-    /// Send a message to address.
+    /// Stop the network host without waiting for the task, which may still be binding the socket.
+    /// The task exits on its own once it gets to the disconnect.
+    pub fn cancel(self) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Action::Disconnect) {
+            error!("Failed to cancel the network task, its action queue is full");
+        }
+    }
+
+    /// Send a message to address. Returns the identifier reported by [`Event::SendFailed`].
     pub fn send(
         &mut self,
         m0: UserMessage,
         m1: UserMessage,
         addr: SocketAddr,
         a: Option<Scalar>,
-    ) -> Result<()> {
-        self.sender.blocking_send(Action::Send(addr, m0, m1, a))?;
-        Ok(())
+    ) -> Result<MessageId> {
+        let id = MessageId(self.next_id);
+        self.sender.try_send(Action::Send(id, addr, m0, m1, a))?;
+        self.next_id += 1;
+        Ok(id)
     }
 
     /// Poll for network events.
     pub fn poll_event(&mut self) -> Option<Event> {
-        self.receiver.try_recv().ok()
+        let event = self.receiver.try_recv().ok();
+        if let Some(Event::LocalAddress(address)) = &event {
+            self.local_address = Some(*address);
+        }
+        event
+    }
+
+    /// Check if the network task is still running.
+    pub fn is_alive(&self) -> bool {
+        !self.join_handle.is_finished() && !self.sender.is_closed()
+    }
+
+    /// Get the status of the network host.
+    pub fn status(&self) -> NetworkStatus {
+        match (self.is_alive(), self.local_address) {
+            (false, _) => NetworkStatus::Failed,
+            (true, None) => NetworkStatus::Connecting,
+            (true, Some(address)) => NetworkStatus::Connected(address),
+        }
+    }
+
+    /// Get the peers currently known to the network task.
+    pub fn peers(&self) -> Vec<Peer> {
+        let peers = self.peers.lock().unwrap();
+        peers.values().map(|(peer, _)| peer.clone()).collect()
+    }
+
+    /// Get the time the peer at the address was last heard from.
+    pub fn last_seen(&self, addr: &SocketAddr) -> Option<Instant> {
+        self.peers.lock().unwrap().get(addr).map(|(_, seen)| *seen)
     }
 
     /// Get the username of the network host.
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Get the address the network host is reachable at, once the socket is bound.
+    pub fn local_address(&self) -> Option<SocketAddr> {
+        self.local_address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::ECHO_ADDRESS;
+    use super::*;
+
+    fn message() -> UserMessage {
+        UserMessage::try_from("message".to_string()).unwrap()
+    }
+
+    fn peer_addr() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 12345))
+    }
+
+    #[test]
+    fn full_channel_is_reported() {
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let name = Username::new("test".to_string()).unwrap();
+        let mut host = NetworkHost::start(name, move |action, _, _, _| {
+            // Stand in for a task that is busy until released.
+            let _ = wait.recv();
+            drop(action);
+        });
+
+        // The initial broadcast takes the first slot.
+        for _ in 1..CHANNEL_SIZE {
+            host.heartbeat().unwrap();
+        }
+        assert!(matches!(
+            host.refresh_hosts(),
+            Err(NetworkError::ChannelFull)
+        ));
+        let addr = peer_addr();
+        assert!(matches!(
+            host.send(message(), message(), addr, None),
+            Err(NetworkError::ChannelFull)
+        ));
+        assert_eq!(host.next_id, 0);
+
+        release.send(()).unwrap();
+        host.join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn disconnect_during_transfer_returns() {
+        let name = Username::new("test".to_string()).unwrap();
+        let mut host =
+            NetworkHost::new_loopback(Context::default(), name, NetworkOptions::default());
+
+        host.send(message(), message(), ECHO_ADDRESS, None).unwrap();
+        let started = Instant::now();
+        host.disconnect().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
 }
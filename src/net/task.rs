@@ -1,62 +1,214 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use local_ip_address::local_ip;
+use p256::elliptic_curve::{Field, PrimeField};
+use p256::{ProjectivePoint as CurvePoint, Scalar};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use tokio::net::lookup_host;
+use tokio::runtime::Builder;
 use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{error, warn};
+use tokio::time::sleep_until;
+use tracing::field::Empty;
+use tracing::{debug, error, info_span, warn, Instrument, Span};
+use zeroize::Zeroizing;
 
 use crate::UiContext as Context;
 
-use super::{Action, Event, Message, MessageState, NetworkError, OTMPSocket, Peer, Username};
+use super::{
+    bytes_to_greet, greet_to_bytes, point_to_bytes, short_authentication_string, Action,
+    CryptoError, Discovery, Event, Loopback, Mdns, Message, MessageId, MessageState, NameSecret,
+    NetworkError, NetworkOptions, OTMPSocket, Peer, PublicKey, RejectReason, Signature, Suite,
+    Transport, UserMessage, Username, WithEcho, SUPPORTED_SUITES,
+};
+#[cfg(feature = "signing")]
+use super::{verify, Identity};
+
+/// Grace window of an unanswered outgoing session. Within it new messages to the same peer wait
+/// behind the session and its greeting is resent when the peer is heard from again, so a brief
+/// network blip does not fail the transfer. Twice the default heartbeat interval of the GUI.
+static SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time a cancelled or timed out outgoing session is remembered, so a late response to it is
+/// dropped quietly instead of being reported as an incorrect message.
+static EXPIRED_WINDOW: Duration = Duration::from_secs(10);
+
+/// Time a session message is remembered to drop repeated copies of it.
+static DUPLICATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Time after which a peer whose greeting was rejected is told again.
+static REJECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time peers have to answer a refresh broadcast before they are dropped.
+static REFRESH_WINDOW: Duration = Duration::from_secs(3);
+
+/// Longest random delay before answering a broadcast greeting, so the peers hearing a refresh do
+/// not all answer in the same instant. Well within the refresh window.
+static RESPONSE_JITTER: Duration = Duration::from_millis(500);
+
+/// Time blocking work started by the task gets to finish when it ends. Spawned tasks are aborted
+/// right away.
+static SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Message waiting for the session in flight with its peer to end.
+type QueuedMessage = (MessageId, UserMessage, UserMessage, Option<Scalar>);
+
+/// Peers known to the network task with the time they were last heard from,
+/// shared with the network host.
+pub(super) type PeerList = Arc<Mutex<BTreeMap<SocketAddr, (Peer, Instant)>>>;
 
 #[derive(Debug)]
-pub(super) struct NetworkTask {
+pub(super) struct NetworkTask<T> {
     states: HashMap<SocketAddr, MessageState>,
+    choices: HashMap<SocketAddr, CurvePoint>,
+    sessions: HashMap<SocketAddr, (MessageId, Instant)>,
+    queued: HashMap<SocketAddr, VecDeque<QueuedMessage>>,
+    expired: HashMap<SocketAddr, Instant>,
+    recent: HashMap<(SocketAddr, [u8; 32]), Instant>,
+    pings: HashMap<SocketAddr, (u64, Instant)>,
+    rejected: HashMap<SocketAddr, Instant>,
+    responses: HashMap<SocketAddr, Instant>,
+    keys: HashMap<SocketAddr, PublicKey>,
+    suites: HashMap<SocketAddr, Vec<Suite>>,
+    blocked_addresses: BTreeSet<IpAddr>,
+    blocked_names: BTreeSet<Username>,
+    peers: PeerList,
+    refresh: Option<Instant>,
+    default_choice: Option<bool>,
+    export_secrets: bool,
+    #[cfg(feature = "signing")]
+    identity: Identity,
     receiver: Receiver<Action>,
     sender: Sender<Event>,
-    socket: OTMPSocket,
+    socket: T,
     context: Context,
     name: Username,
+    options: NetworkOptions,
+    mdns: Option<Mdns>,
+    name_secret: NameSecret,
 }
 
-impl NetworkTask {
+impl NetworkTask<WithEcho<OTMPSocket>> {
     /// Run task blocking current thread.
-    #[tokio::main(flavor = "current_thread")]
-    pub async fn run(
+    pub fn run(
         receiver: Receiver<Action>,
         sender: Sender<Event>,
         name: Username,
+        peers: PeerList,
         context: Context,
-        port: u16,
+        bind: SocketAddr,
+        options: NetworkOptions,
     ) {
-        let socket = match OTMPSocket::bind(port).await {
-            Ok(socket) => socket,
-            Err(error) => {
-                warn!("Unable to create socket: {error}");
-                send_event(&sender, Event::Error(NetworkError::SocketBindError(error))).await;
-                return;
+        block_on(async move {
+            let socket = if options.port_fallback {
+                OTMPSocket::bind_from(bind.ip(), bind.port()).await
+            } else {
+                OTMPSocket::bind_to(bind.ip(), bind.port()).await
+            };
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(error) => {
+                    warn!("Unable to create socket: {error}");
+                    send_event(&sender, Event::Error(NetworkError::SocketBindError(error))).await;
+                    context.request_repaint();
+                    return;
+                }
+            };
+
+            let socket = WithEcho::new(socket);
+            let mut task = Self::new(receiver, sender, name, peers, context, socket, options);
+            if options.discovery == Discovery::Mdns {
+                task.start_mdns().await;
             }
-        };
+            task.main_loop().await;
+        });
+    }
+}
+
+impl NetworkTask<Loopback> {
+    /// Run task with an in-process loopback transport blocking current thread.
+    pub fn run_loopback(
+        receiver: Receiver<Action>,
+        sender: Sender<Event>,
+        name: Username,
+        peers: PeerList,
+        context: Context,
+        options: NetworkOptions,
+    ) {
+        let socket = Loopback::new();
+        block_on(Self::new(receiver, sender, name, peers, context, socket, options).main_loop());
+    }
+}
 
-        let task = Self {
+impl<T: Transport> NetworkTask<T> {
+    fn new(
+        receiver: Receiver<Action>,
+        sender: Sender<Event>,
+        name: Username,
+        peers: PeerList,
+        context: Context,
+        socket: T,
+        options: NetworkOptions,
+    ) -> Self {
+        Self {
             states: HashMap::new(),
+            choices: HashMap::new(),
+            sessions: HashMap::new(),
+            queued: HashMap::new(),
+            expired: HashMap::new(),
+            recent: HashMap::new(),
+            pings: HashMap::new(),
+            rejected: HashMap::new(),
+            responses: HashMap::new(),
+            keys: HashMap::new(),
+            suites: HashMap::new(),
+            blocked_addresses: BTreeSet::new(),
+            blocked_names: BTreeSet::new(),
+            peers,
+            refresh: None,
+            default_choice: None,
+            export_secrets: false,
+            #[cfg(feature = "signing")]
+            identity: Identity::generate(),
             receiver,
             sender,
             socket,
             context,
             name,
-        };
-
-        task.main_loop().await;
+            options,
+            mdns: None,
+            name_secret: NameSecret::generate(),
+        }
     }
 
     async fn main_loop(mut self) {
+        match self.socket.local_addr() {
+            Ok(address) => self.send_event(Event::LocalAddress(address)).await,
+            Err(error) => self.send_error(error).await,
+        }
+
         let mut running = true;
         while running {
+            let refresh_end = self
+                .refresh
+                .map_or_else(Instant::now, |started| started + REFRESH_WINDOW);
+            let next_response = self.responses.values().min().copied();
+            let next_timeout = self.next_timeout();
             let result = select! {
                 result = self.socket.recv_from() => match result {
-                    Ok((message, sender)) => self.on_packet(message, sender).await,
+                    Ok((message, sender)) => {
+                        let span = self.session_span(sender);
+                        self.on_packet(message, sender).instrument(span).await
+                    }
+                    Err(error @ NetworkError::InvalidName(sender, _)) => {
+                        debug!("{error}");
+                        self.reject(sender, RejectReason::InvalidName).await
+                    }
                     Err(error) => Err(error)
                 },
                 action = self.receiver.recv() => match action {
@@ -71,6 +223,24 @@ impl NetworkTask {
                         running = false;
                         Ok(())
                     }
+                },
+                addrs = next_host(self.mdns.as_ref()) => {
+                    self.on_host_found(addrs).await
+                }
+                _ = sleep_until(refresh_end.into()), if self.refresh.is_some() => {
+                    self.prune_peers().await;
+                    Ok(())
+                }
+                _ = sleep_until(next_response.unwrap_or_else(Instant::now).into()),
+                    if next_response.is_some() =>
+                {
+                    self.send_responses(Instant::now()).await
+                }
+                _ = sleep_until(next_timeout.unwrap_or_else(Instant::now).into()),
+                    if next_timeout.is_some() =>
+                {
+                    self.time_out_sessions().await;
+                    Ok(())
                 }
             };
 
@@ -80,6 +250,53 @@ impl NetworkTask {
         }
     }
 
+    /// Announce the host over multicast DNS. If it fails, discovery is reported unavailable.
+    async fn start_mdns(&mut self) {
+        let result = self
+            .socket
+            .local_addr()
+            .and_then(|addr| Mdns::start(&self.socket.local_ips()?, addr.port()));
+        match result {
+            Ok(mdns) => self.mdns = Some(mdns),
+            Err(error) => {
+                warn!("Unable to start mDNS: {error}");
+                self.send_event(Event::DiscoveryUnavailable(error)).await;
+            }
+        }
+    }
+
+    /// Greet a host found over multicast DNS at the first of its addresses, unless it is own or
+    /// already known.
+    async fn on_host_found(&mut self, addrs: Vec<SocketAddr>) -> Result<(), NetworkError> {
+        if addrs
+            .iter()
+            .any(|addr| self.peers.lock().unwrap().contains_key(addr))
+        {
+            return Ok(());
+        }
+        for &addr in &addrs {
+            if self.is_own(addr)? {
+                return Ok(());
+            }
+        }
+        match addrs.first() {
+            Some(&addr) => {
+                let message = self.greet_message();
+                self.socket.send_to(message, addr).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Span grouping the log lines of a transfer with the peer, with the id if one is sent.
+    fn session_span(&self, addr: SocketAddr) -> Span {
+        let span = info_span!("session", peer = %addr, id = Empty);
+        if let Some((id, _)) = self.sessions.get(&addr) {
+            span.record("id", id.0);
+        }
+        span
+    }
+
     async fn send_error(&self, error: NetworkError) {
         self.send_event(Event::Error(error)).await;
     }
@@ -90,72 +307,866 @@ impl NetworkTask {
     }
 
     async fn on_packet(&mut self, message: Message, addr: SocketAddr) -> Result<(), NetworkError> {
-        match message {
-            Message::BroadcastGreet(name) => {
-                if local_ip()? != addr.ip() {
-                    let peer = Peer::new_with_name(addr, name);
-                    self.send_event(Event::Connected(peer)).await;
+        let name = match &message {
+            Message::BroadcastGreet(name, ..) | Message::BroadcastResponse(name, ..) => Some(name),
+            _ => None,
+        };
+        if self.is_blocked(addr, name) {
+            debug!("Dropping message from blocked {addr}");
+            return Ok(());
+        }
 
-                    let message = Message::BroadcastResponse(self.name.clone());
-                    self.socket.send_to(message, addr).await?;
+        if let Some((_, last_seen)) = self.peers.lock().unwrap().get_mut(&addr) {
+            *last_seen = Instant::now();
+        }
+
+        if self.is_duplicate(addr, &message) {
+            debug!("Dropping duplicate message from {addr}");
+            return Ok(());
+        }
+
+        let presence = matches!(
+            message,
+            Message::BroadcastGreet(..)
+                | Message::BroadcastResponse(..)
+                | Message::HiddenGreet(_)
+                | Message::HiddenResponse(..)
+                | Message::HiddenReveal(..)
+        );
+        if presence {
+            self.resume_session(addr).await?;
+        }
+
+        match message {
+            Message::BroadcastGreet(name, key, suites) => {
+                if !self.is_own(addr)? {
+                    self.store_greeting(addr, key, suites);
+                    self.on_connected(Peer::new_with_name(addr, name)).await;
+                    self.queue_response(addr);
                 }
                 Ok(())
             }
-            Message::BroadcastResponse(name) => {
-                let peer = Peer::new_with_name(addr, name);
-                self.send_event(Event::Connected(peer)).await;
+            Message::BroadcastResponse(name, key, suites) => {
+                self.store_greeting(addr, key, suites);
+                self.on_connected(Peer::new_with_name(addr, name)).await;
+                Ok(())
+            }
+            Message::HiddenGreet(point) => {
+                if !self.is_own(addr)? {
+                    let name = self.encrypt_name(point);
+                    let message = Message::HiddenResponse(self.name_secret.public(), name);
+                    self.socket.send_to(message, addr).await?;
+                }
                 Ok(())
             }
+            Message::HiddenResponse(point, name) => {
+                self.on_hidden_name(addr, point, &name).await?;
+                let name = self.encrypt_name(point);
+                let message = Message::HiddenReveal(self.name_secret.public(), name);
+                self.socket.send_to(message, addr).await
+            }
+            Message::HiddenReveal(point, name) => self.on_hidden_name(addr, point, &name).await,
             Message::BroadcastBye => {
-                if local_ip()? != addr.ip() {
+                if !self.is_own(addr)? {
+                    self.peers.lock().unwrap().remove(&addr);
                     self.send_event(Event::Disconnected(addr)).await;
                 }
                 Ok(())
             }
             Message::Greet(point) => {
-                let (response, state) = MessageState::on_greeting(point);
-                self.states.insert(addr, state);
-                let response = Message::Response(response);
-                self.socket.send_to(response, addr).await?;
-                Ok(())
-            }
-            Message::Response(point) => match self.states.remove(&addr) {
-                Some(state) => {
-                    let (m0, m1) = state
-                        .on_response(point)
-                        .map_err(|_| NetworkError::IncorrectMessage(addr))?;
-                    self.socket.send_to(Message::Data(m0, m1), addr).await?;
-                    Ok(())
+                if !self.accepts_greet(addr) {
+                    debug!("Rejecting greet from {addr} during an outgoing session");
+                    return self.reject(addr, RejectReason::Busy).await;
                 }
-                None => Err(NetworkError::IncorrectMessage(addr)),
-            },
-            Message::Data(m0, m1) => match self.states.remove(&addr) {
-                Some(state) => {
-                    let message = state
-                        .on_messages(m0, m1)
-                        .map_err(|_| NetworkError::IncorrectMessage(addr))?;
-                    self.send_event(Event::Message(addr, message)).await;
-                    Ok(())
+                // A repeated greet means the sender resumed the session after missing a step.
+                if let Some(MessageState::GreetReceived(_, _, greet, response)) =
+                    self.states.get(&addr)
+                {
+                    if *greet == point {
+                        debug!("Resending response to {addr}");
+                        let response = Message::Response(*response);
+                        return self.socket.send_to(response, addr).await;
+                    }
                 }
-                None => Err(NetworkError::IncorrectMessage(addr)),
-            },
+                if self.choices.get(&addr) == Some(&point) {
+                    debug!("Greet from {addr} is already waiting for a choice");
+                    return Ok(());
+                }
+                if let Some(c) = self.default_choice {
+                    self.choices.remove(&addr);
+                    return self.answer_greet(addr, point, c).await;
+                }
+                // The response is deferred until the user picks a message with `Action::Choose`.
+                self.states.remove(&addr);
+                self.choices.insert(addr, point);
+                self.send_event(Event::ChoiceRequested(addr)).await;
+                Ok(())
+            }
+            Message::Response(point) => {
+                // A stray response must not end an incoming transfer with the peer.
+                match self.states.get(&addr) {
+                    Some(MessageState::GreetSent(..)) => {}
+                    _ if self.is_expired(addr) => {
+                        debug!("Ignoring late response from {addr} to an expired session");
+                        return Ok(());
+                    }
+                    Some(MessageState::GreetReceived(..)) => {
+                        debug!("Ignoring response from {addr} during an incoming session");
+                        return Ok(());
+                    }
+                    None => {
+                        debug!("Ignoring response from {addr} without a session");
+                        return Ok(());
+                    }
+                }
+                let Some(state) = self.states.remove(&addr) else {
+                    return Ok(());
+                };
+                let result = self.on_response(addr, state, point).await;
+                match self.sessions.remove(&addr) {
+                    Some((id, _)) => {
+                        let event = match result {
+                            Ok(()) => Event::Delivered(id),
+                            Err(error) => Event::SendFailed(id, error),
+                        };
+                        self.send_event(event).await;
+                        self.send_queued(addr).await;
+                        Ok(())
+                    }
+                    None => result,
+                }
+            }
+            // Unknown suites fail to parse, the data is decrypted with the one the sender picked.
+            Message::Data(suite, m0, m1) => self.on_data(addr, suite, m0, m1, false).await,
+            Message::SignedData(suite, m0, m1, signature) => {
+                let verified = self.verify_data(addr, &m0, &m1, &signature)?;
+                self.on_data(addr, suite, m0, m1, verified).await
+            }
+            Message::Reject(RejectReason::Busy)
+                if matches!(self.states.get(&addr), Some(MessageState::GreetSent(..))) =>
+            {
+                // The peer is sending a message itself, the greetings crossed.
+                self.states.remove(&addr);
+                if let Some((id, _)) = self.sessions.remove(&addr) {
+                    let error = NetworkError::PeerBusy(addr);
+                    self.send_event(Event::SendFailed(id, error)).await;
+                }
+                self.fail_queued(addr, NetworkError::PeerBusy).await;
+                Ok(())
+            }
+            Message::Reject(reason) => {
+                self.send_event(Event::Rejected(addr, reason)).await;
+                Ok(())
+            }
+            Message::Ping(nonce) => self.socket.send_to(Message::Pong(nonce), addr).await,
+            Message::Announcement(text) => {
+                if !self.is_own(addr)? {
+                    self.send_event(Event::Announcement(addr, text.into()))
+                        .await;
+                }
+                Ok(())
+            }
+            Message::Pong(nonce) => {
+                match self.pings.remove(&addr) {
+                    Some((sent, started)) if sent == nonce => {
+                        self.send_event(Event::Pong(addr, started.elapsed())).await;
+                    }
+                    Some(ping) => {
+                        debug!("Ignoring pong from {addr} to an earlier ping");
+                        self.pings.insert(addr, ping);
+                    }
+                    None => debug!("Ignoring pong from {addr} without a ping"),
+                }
+                Ok(())
+            }
         }
     }
 
+    async fn on_response(
+        &mut self,
+        addr: SocketAddr,
+        state: MessageState,
+        point: CurvePoint,
+    ) -> Result<(), NetworkError> {
+        let suite = self.suite(addr)?;
+        let greet = match &state {
+            MessageState::GreetSent(_, greet, _, _) => *greet,
+            MessageState::GreetReceived(..) => return Err(NetworkError::IncorrectMessage(addr)),
+        };
+        let (m0, m1, hash) = state
+            .on_response(suite, point)
+            .map_err(|_| NetworkError::IncorrectMessage(addr))?;
+        let ciphertext = Event::Ciphertext(addr, m0.clone(), m1.clone());
+        self.socket
+            .send_to(self.data_message(suite, m0, m1), addr)
+            .await?;
+        let sas = Event::Sas(addr, short_authentication_string(greet, point));
+        let points = Event::Points(addr, point_to_bytes(greet), point_to_bytes(point));
+        self.send_event(points).await;
+        self.send_event(sas).await;
+        self.send_event(ciphertext).await;
+        self.send_event(Event::Transcript(addr, hash)).await;
+        Ok(())
+    }
+
+    /// Check if the same session message was received from the address within the window.
+    /// Presence messages are repeated on purpose and already idempotent, and a repeated greet
+    /// resumes a session, so they are never dropped.
+    fn is_duplicate(&mut self, addr: SocketAddr, message: &Message) -> bool {
+        self.recent
+            .retain(|_, seen| seen.elapsed() < DUPLICATE_WINDOW);
+        if !matches!(
+            message,
+            Message::Response(_) | Message::Data(..) | Message::SignedData(..)
+        ) {
+            return false;
+        }
+        let Ok(bytes) = message.clone().try_into_bytes() else {
+            return false;
+        };
+        let hash = Sha256::digest(bytes).into();
+        self.recent.insert((addr, hash), Instant::now()).is_some()
+    }
+
+    /// Answer a broadcast greeting after a random delay. Greetings repeated before the answer is
+    /// sent share it, so a burst of refreshes is answered once.
+    fn queue_response(&mut self, addr: SocketAddr) {
+        let delay = thread_rng().gen_range(Duration::ZERO..=RESPONSE_JITTER);
+        self.responses
+            .entry(addr)
+            .or_insert_with(|| Instant::now() + delay);
+    }
+
+    /// Send the answers to broadcast greetings that are due by `now`.
+    async fn send_responses(&mut self, now: Instant) -> Result<(), NetworkError> {
+        let due: Vec<SocketAddr> = self
+            .responses
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in due {
+            self.responses.remove(&addr);
+            let message = if self.options.hide_name {
+                Message::HiddenGreet(self.name_secret.public())
+            } else {
+                let suites = SUPPORTED_SUITES.to_vec();
+                Message::BroadcastResponse(self.name.clone(), self.public_key(), suites)
+            };
+            self.socket.send_to(message, addr).await?;
+        }
+        Ok(())
+    }
+
+    /// Tell a peer its greeting was dropped. Only directed greetings are rejected, so a broadcast
+    /// is not answered by every host. A reject is still smaller than any greeting and sent at most
+    /// once per interval to an address.
+    async fn reject(&mut self, addr: SocketAddr, reason: RejectReason) -> Result<(), NetworkError> {
+        self.rejected
+            .retain(|_, sent| sent.elapsed() < REJECT_INTERVAL);
+        if self.rejected.contains_key(&addr) {
+            debug!("Already rejected {addr} recently");
+            return Ok(());
+        }
+        self.rejected.insert(addr, Instant::now());
+        self.socket.send_to(Message::Reject(reason), addr).await
+    }
+
+    /// Resend the greeting of an unanswered session within the grace window. Only the handshake
+    /// can be resumed, the data is not acknowledged so a lost one cannot be detected.
+    async fn resume_session(&mut self, addr: SocketAddr) -> Result<(), NetworkError> {
+        match (self.sessions.get(&addr), self.states.get(&addr)) {
+            (Some((_, started)), Some(MessageState::GreetSent(_, point, _, _)))
+                if started.elapsed() < SESSION_TIMEOUT =>
+            {
+                debug!("Resuming session with {addr}");
+                let greet = Message::Greet(*point);
+                self.socket.send_to(greet, addr).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// End a refresh, dropping the peers not heard from since it started.
+    async fn prune_peers(&mut self) {
+        let Some(started) = self.refresh.take() else {
+            return;
+        };
+        let silent: Vec<SocketAddr> = {
+            let mut peers = self.peers.lock().unwrap();
+            let silent = peers
+                .iter()
+                .filter(|(_, (_, seen))| *seen < started)
+                .map(|(addr, _)| *addr)
+                .collect();
+            peers.retain(|_, (_, seen)| *seen >= started);
+            silent
+        };
+        for addr in silent {
+            self.send_event(Event::Disconnected(addr)).await;
+        }
+        self.send_event(Event::Refreshed).await;
+    }
+
+    /// Record the peer as seen. Only a new peer or a changed name is reported.
+    async fn on_connected(&self, peer: Peer) {
+        let known = self
+            .peers
+            .lock()
+            .unwrap()
+            .insert(peer.address(), (peer.clone(), Instant::now()));
+        match (known, peer.name()) {
+            (None, _) => self.send_event(Event::Connected(peer)).await,
+            (Some((known, _)), Some(name)) if known.name() != Some(name) => {
+                let event = Event::PeerRenamed(peer.address(), name.clone());
+                self.send_event(event).await;
+            }
+            _ => {}
+        }
+    }
+
+    async fn on_data(
+        &mut self,
+        addr: SocketAddr,
+        suite: Suite,
+        m0: Vec<u8>,
+        m1: Vec<u8>,
+        verified: bool,
+    ) -> Result<(), NetworkError> {
+        match self.states.get(&addr) {
+            Some(state) => {
+                let ciphertext = Event::Ciphertext(addr, m0.clone(), m1.clone());
+                let result = state.on_messages(suite, m0, m1);
+                // A replayed packet must not end the session the real data is still coming for.
+                if let Err(CryptoError::Replay) = result {
+                    return Err(NetworkError::CryptoError(addr, CryptoError::Replay));
+                }
+                self.states.remove(&addr);
+                self.send_event(ciphertext).await;
+                if let Err(CryptoError::Undecryptable) = result {
+                    warn!("Message from {addr} could not be decrypted");
+                    self.send_event(Event::DecryptFailed(addr)).await;
+                    return Ok(());
+                }
+                let (message, hash) = result.map_err(|_| NetworkError::IncorrectMessage(addr))?;
+                self.send_event(Event::Message(addr, message, verified))
+                    .await;
+                self.send_event(Event::Transcript(addr, hash)).await;
+                Ok(())
+            }
+            None => {
+                debug!("Ignoring data from {addr} without a session");
+                Ok(())
+            }
+        }
+    }
+
+    fn encrypt_name(&self, point: CurvePoint) -> Vec<u8> {
+        let name = greet_to_bytes(&self.name, self.public_key(), SUPPORTED_SUITES);
+        self.name_secret.encrypt(point, &name)
+    }
+
+    async fn on_hidden_name(
+        &mut self,
+        addr: SocketAddr,
+        point: CurvePoint,
+        name: &[u8],
+    ) -> Result<(), NetworkError> {
+        let (name, key, suites) = bytes_to_greet(&self.name_secret.decrypt(point, name))?;
+        if self.is_blocked(addr, Some(&name)) {
+            debug!("Dropping hidden name of blocked {addr}");
+            return Ok(());
+        }
+        self.store_greeting(addr, key, suites);
+        self.on_connected(Peer::new_with_name(addr, name)).await;
+        Ok(())
+    }
+
+    /// Check if the address is blocked, or the name it announces or is known by.
+    fn is_blocked(&self, addr: SocketAddr, name: Option<&Username>) -> bool {
+        if self.blocked_addresses.contains(&addr.ip()) {
+            return true;
+        }
+        let peers = self.peers.lock().unwrap();
+        let known = peers.get(&addr).and_then(|(peer, _)| peer.name());
+        let blocked = |name: &Username| self.blocked_names.contains(name);
+        name.is_some_and(blocked) || known.is_some_and(blocked)
+    }
+
+    /// Drop the transfers in progress with the peer, failing a message still being sent.
+    async fn cancel_peer(&mut self, addr: SocketAddr) {
+        self.fail_queued(addr, NetworkError::SessionCancelled).await;
+        self.cancel_session(addr).await;
+        self.states.remove(&addr);
+        self.choices.remove(&addr);
+        self.pings.remove(&addr);
+    }
+
+    /// Drop the outgoing transfer to the peer, reporting its message as cancelled.
+    async fn cancel_session(&mut self, addr: SocketAddr) {
+        if let Some(MessageState::GreetSent(..)) = self.states.get(&addr) {
+            self.states.remove(&addr);
+            self.expire(addr);
+        }
+        if let Some((id, _)) = self.sessions.remove(&addr) {
+            let error = NetworkError::SessionCancelled(addr);
+            self.send_event(Event::SendFailed(id, error)).await;
+        }
+    }
+
+    /// Fail the messages waiting to be sent to the peer.
+    async fn fail_queued(&mut self, addr: SocketAddr, error: fn(SocketAddr) -> NetworkError) {
+        for (id, ..) in self.queued.remove(&addr).unwrap_or_default() {
+            self.send_event(Event::SendFailed(id, error(addr))).await;
+        }
+    }
+
+    /// Start sending the next message waiting for the peer, skipping the ones that fail to start.
+    async fn send_queued(&mut self, addr: SocketAddr) {
+        while !self.sessions.contains_key(&addr) {
+            let Some(queue) = self.queued.get_mut(&addr) else {
+                return;
+            };
+            let next = queue.pop_front();
+            if queue.is_empty() {
+                self.queued.remove(&addr);
+            }
+            if let Some(message) = next {
+                self.start_send(addr, message).await;
+            }
+        }
+    }
+
+    /// End of the grace window of the earliest session with messages waiting behind it.
+    fn next_timeout(&self) -> Option<Instant> {
+        self.queued
+            .keys()
+            .filter_map(|addr| self.sessions.get(addr))
+            .map(|(_, started)| *started + SESSION_TIMEOUT)
+            .min()
+    }
+
+    /// Fail the sessions past their grace window that messages wait behind, and send those.
+    async fn time_out_sessions(&mut self) {
+        let timed_out: Vec<SocketAddr> = self
+            .queued
+            .keys()
+            .filter(|addr| {
+                self.sessions
+                    .get(addr)
+                    .is_some_and(|(_, started)| started.elapsed() >= SESSION_TIMEOUT)
+            })
+            .copied()
+            .collect();
+        for addr in timed_out {
+            self.time_out(addr).await;
+        }
+    }
+
+    /// Fail the unanswered session with the peer and start sending the next message waiting.
+    async fn time_out(&mut self, addr: SocketAddr) {
+        if let Some((id, _)) = self.sessions.remove(&addr) {
+            self.states.remove(&addr);
+            self.expire(addr);
+            let error = NetworkError::SessionTimeout(addr);
+            self.send_event(Event::SendFailed(id, error)).await;
+        }
+        self.send_queued(addr).await;
+    }
+
+    /// Check if a greeting from the peer can be answered. It counts against `max_sessions` along
+    /// with the messages to the peer, and none can be received while one is sent.
+    fn accepts_greet(&self, addr: SocketAddr) -> bool {
+        let outgoing = matches!(self.states.get(&addr), Some(MessageState::GreetSent(..)));
+        let queued = self.queued.get(&addr).map_or(0, VecDeque::len);
+        let sessions = usize::from(self.sessions.contains_key(&addr)) + queued;
+        !outgoing && sessions < self.options.max_sessions.max(1)
+    }
+
+    /// Remember that the outgoing session with the peer ended without a response.
+    fn expire(&mut self, addr: SocketAddr) {
+        self.expired
+            .retain(|_, expired| expired.elapsed() < EXPIRED_WINDOW);
+        self.expired.insert(addr, Instant::now());
+    }
+
+    /// Check if an outgoing session with the peer ended recently without a response.
+    fn is_expired(&self, addr: SocketAddr) -> bool {
+        self.expired
+            .get(&addr)
+            .is_some_and(|expired| expired.elapsed() < EXPIRED_WINDOW)
+    }
+
+    /// Greeting announcing the host, without the name if it is hidden.
+    fn greet_message(&self) -> Message {
+        if self.options.hide_name {
+            Message::HiddenGreet(self.name_secret.public())
+        } else {
+            let suites = SUPPORTED_SUITES.to_vec();
+            Message::BroadcastGreet(self.name.clone(), self.public_key(), suites)
+        }
+    }
+
+    fn store_greeting(&mut self, addr: SocketAddr, key: Option<PublicKey>, suites: Vec<Suite>) {
+        match key {
+            Some(key) => self.keys.insert(addr, key),
+            None => self.keys.remove(&addr),
+        };
+        self.suites.insert(addr, suites);
+    }
+
+    /// Suite of a transfer to the peer. A peer not heard from yet is assumed to support ours, it
+    /// drops the data otherwise.
+    fn suite(&self, addr: SocketAddr) -> Result<Suite, NetworkError> {
+        let theirs = self
+            .suites
+            .get(&addr)
+            .map_or(SUPPORTED_SUITES, Vec::as_slice);
+        Suite::negotiate(theirs).map_err(|error| NetworkError::CryptoError(addr, error))
+    }
+
+    #[cfg(feature = "signing")]
+    fn public_key(&self) -> Option<PublicKey> {
+        Some(self.identity.public_key())
+    }
+
+    #[cfg(not(feature = "signing"))]
+    fn public_key(&self) -> Option<PublicKey> {
+        None
+    }
+
+    #[cfg(feature = "signing")]
+    fn verify_data(
+        &self,
+        addr: SocketAddr,
+        m0: &[u8],
+        m1: &[u8],
+        signature: &Signature,
+    ) -> Result<bool, NetworkError> {
+        let key = self.keys.get(&addr).ok_or(CryptoError::BadSignature);
+        key.and_then(|key| verify(key, m0, m1, signature))
+            .map_err(|error| NetworkError::CryptoError(addr, error))?;
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "signing"))]
+    fn verify_data(
+        &self,
+        _: SocketAddr,
+        _: &[u8],
+        _: &[u8],
+        _: &Signature,
+    ) -> Result<bool, NetworkError> {
+        Ok(false)
+    }
+
+    #[cfg(feature = "signing")]
+    fn data_message(&self, suite: Suite, m0: Vec<u8>, m1: Vec<u8>) -> Message {
+        let signature = self.identity.sign(&m0, &m1);
+        Message::SignedData(suite, m0, m1, signature)
+    }
+
+    #[cfg(not(feature = "signing"))]
+    fn data_message(&self, suite: Suite, m0: Vec<u8>, m1: Vec<u8>) -> Message {
+        Message::Data(suite, m0, m1)
+    }
+
     async fn on_action(&mut self, action: Action) -> Result<(), NetworkError> {
         match action {
             Action::Broadcast => {
-                let message = Message::BroadcastGreet(self.name.clone());
-                self.socket.broadcast(message).await
+                // Known peers are kept until the window ends, so the list does not flash empty.
+                self.refresh = Some(Instant::now());
+                let message = self.greet_message();
+                match self.send_to_all(message).await {
+                    Err(error @ NetworkError::BroadcastAddressNotFound) => {
+                        self.send_event(Event::DiscoveryUnavailable(error)).await;
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }
+            Action::Heartbeat => {
+                let message = self.greet_message();
+                match self.send_to_all(message).await {
+                    // Already reported by the broadcast, repeating it on every heartbeat is noise.
+                    Err(NetworkError::BroadcastAddressNotFound) => Ok(()),
+                    result => result,
+                }
+            }
+            Action::Greet(addr) => {
+                self.check_not_self(addr)?;
+                let message = self.greet_message();
+                self.socket.send_to(message, addr).await
+            }
+            Action::Resolve(host) => match resolve(&host).await {
+                Ok(addr) => {
+                    self.send_event(Event::Resolved(host, addr)).await;
+                    self.check_not_self(addr)?;
+                    let message = self.greet_message();
+                    self.socket.send_to(message, addr).await
+                }
+                Err(error) => {
+                    self.send_event(Event::ResolveFailed(host, error)).await;
+                    Ok(())
+                }
+            },
+            Action::Choose(addr, c) => {
+                let Some(point) = self.choices.remove(&addr) else {
+                    debug!("No greet from {addr} is waiting for a choice");
+                    return Ok(());
+                };
+                if let Some(MessageState::GreetSent(..)) = self.states.get(&addr) {
+                    debug!("Dropping greet from {addr} during an outgoing session");
+                    return Ok(());
+                }
+                self.answer_greet(addr, point, c).await
+            }
+            Action::DefaultChoice(c) => {
+                self.default_choice = c;
+                Ok(())
+            }
+            Action::Ping(addr) => {
+                self.check_not_self(addr)?;
+                let nonce = rand::random();
+                self.pings.insert(addr, (nonce, Instant::now()));
+                self.socket.send_to(Message::Ping(nonce), addr).await
+            }
+            Action::SetName(name) => {
+                self.name = name;
+                // Peers added by address may be outside the broadcast domain, greet them directly.
+                let peers: Vec<SocketAddr> = self.peers.lock().unwrap().keys().copied().collect();
+                for addr in peers {
+                    self.socket.send_to(self.greet_message(), addr).await?;
+                }
+                if self.options.discovery != Discovery::Broadcast {
+                    return Ok(());
+                }
+                match self.socket.broadcast(self.greet_message()).await {
+                    Err(NetworkError::BroadcastAddressNotFound) => Ok(()),
+                    result => result,
+                }
             }
-            Action::Disconnect => self.socket.broadcast(Message::BroadcastBye).await,
-            Action::Send(addr, m0, m1, a) => {
-                let (message, state) = MessageState::send_message(m0, m1, a);
-                self.states.insert(addr, state);
-                self.socket.send_to(Message::Greet(message), addr).await?;
+            Action::CancelPeer(addr) => {
+                self.cancel_peer(addr).await;
                 Ok(())
             }
+            Action::CancelSend(id) => {
+                let session = self
+                    .sessions
+                    .iter()
+                    .find(|(_, (session, _))| *session == id);
+                if let Some((&addr, _)) = session {
+                    self.cancel_session(addr).await;
+                    self.send_queued(addr).await;
+                } else if let Some(addr) = self.unqueue(id) {
+                    let error = NetworkError::SessionCancelled(addr);
+                    self.send_event(Event::SendFailed(id, error)).await;
+                }
+                Ok(())
+            }
+            Action::SetBlocklist(addresses, names) => {
+                self.blocked_addresses = addresses;
+                self.blocked_names = names;
+                let mut blocked: Vec<SocketAddr> =
+                    self.peers.lock().unwrap().keys().copied().collect();
+                blocked.extend(self.states.keys().chain(self.choices.keys()));
+                blocked.retain(|addr| self.is_blocked(*addr, None));
+                blocked.sort();
+                blocked.dedup();
+                for addr in blocked {
+                    self.cancel_peer(addr).await;
+                    if self.peers.lock().unwrap().remove(&addr).is_some() {
+                        self.send_event(Event::Disconnected(addr)).await;
+                    }
+                }
+                Ok(())
+            }
+            Action::ExportSecrets(export) => {
+                self.export_secrets = export;
+                Ok(())
+            }
+            Action::Announce(text) => self.send_to_all(Message::Announcement(text)).await,
+            Action::Disconnect => self.send_to_all(Message::BroadcastBye).await,
+            Action::Send(id, addr, m0, m1, a) => {
+                let timed_out = self
+                    .sessions
+                    .get(&addr)
+                    .is_some_and(|(_, started)| started.elapsed() >= SESSION_TIMEOUT);
+                if timed_out {
+                    self.time_out(addr).await;
+                }
+                if self.sessions.contains_key(&addr) {
+                    self.queue_send(addr, (id, m0, m1, a)).await;
+                } else {
+                    self.start_send(addr, (id, m0, m1, a)).await;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue a message behind the session in flight with the peer, or fail it when the peer has
+    /// `max_sessions` already.
+    async fn queue_send(&mut self, addr: SocketAddr, message: QueuedMessage) {
+        let queue = self.queued.entry(addr).or_default();
+        if 1 + queue.len() < self.options.max_sessions {
+            debug!("Queueing message {} to {addr}", message.0 .0);
+            queue.push_back(message);
+        } else {
+            if queue.is_empty() {
+                self.queued.remove(&addr);
+            }
+            let error = NetworkError::SessionInFlight(addr);
+            self.send_event(Event::SendFailed(message.0, error)).await;
+        }
+    }
+
+    /// Remove a message waiting to be sent. Returns the address of its peer if it was queued.
+    fn unqueue(&mut self, id: MessageId) -> Option<SocketAddr> {
+        let (&addr, queue) = self
+            .queued
+            .iter_mut()
+            .find(|(_, queue)| queue.iter().any(|(queued, ..)| *queued == id))?;
+        queue.retain(|(queued, ..)| *queued != id);
+        if queue.is_empty() {
+            self.queued.remove(&addr);
+        }
+        Some(addr)
+    }
+
+    /// Greet the peer to send it a message, failing the message if the greeting cannot be sent.
+    async fn start_send(&mut self, addr: SocketAddr, message: QueuedMessage) {
+        let (id, m0, m1, a) = message;
+        let span = info_span!("session", peer = %addr, id = id.0);
+        match self.send(addr, m0, m1, a).instrument(span).await {
+            Ok(()) => {
+                self.sessions.insert(addr, (id, Instant::now()));
+            }
+            Err(error) => {
+                self.states.remove(&addr);
+                self.send_event(Event::SendFailed(id, error)).await;
+            }
+        }
+    }
+
+    /// Send the message to everyone, broadcast or to each known peer when discovery does not
+    /// broadcast.
+    async fn send_to_all(&mut self, message: Message) -> Result<(), NetworkError> {
+        match self.options.discovery {
+            Discovery::Broadcast => self.socket.broadcast(message).await,
+            Discovery::Directed | Discovery::Mdns => {
+                let peers: Vec<SocketAddr> = self.peers.lock().unwrap().keys().copied().collect();
+                for addr in peers {
+                    self.socket.send_to(message.clone(), addr).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Respond to a greet, receiving the second message of the pair if `c` is set.
+    async fn answer_greet(
+        &mut self,
+        addr: SocketAddr,
+        point: CurvePoint,
+        c: bool,
+    ) -> Result<(), NetworkError> {
+        let b = Zeroizing::new(Scalar::random(&mut thread_rng()));
+        let (response, state) = MessageState::on_greeting_with_scalar(point, c, *b);
+        self.states.insert(addr, state);
+        self.report_scalar(addr, &b).await;
+        self.socket
+            .send_to(Message::Response(response), addr)
+            .await?;
+        let points = Event::Points(addr, point_to_bytes(point), point_to_bytes(response));
+        self.send_event(points).await;
+        let sas = Event::Sas(addr, short_authentication_string(point, response));
+        self.send_event(sas).await;
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        addr: SocketAddr,
+        m0: UserMessage,
+        m1: UserMessage,
+        a: Option<Scalar>,
+    ) -> Result<(), NetworkError> {
+        self.check_not_self(addr)?;
+        // Fail before the handshake, the peer could not read the data.
+        self.suite(addr)?;
+        let (message, state) = MessageState::send_message(m0, m1, a);
+        if let MessageState::GreetSent(a, ..) = &state {
+            self.report_scalar(addr, a).await;
+        }
+        self.states.insert(addr, state);
+        self.socket.send_to(Message::Greet(message), addr).await
+    }
+
+    /// Report the secret scalar of a transfer starting with the peer, if secrets are exported.
+    async fn report_scalar(&self, addr: SocketAddr, scalar: &Scalar) {
+        if self.export_secrets {
+            let event = Event::Scalar(addr, scalar.to_repr().to_vec());
+            self.send_event(event).await;
+        }
+    }
+
+    /// Check if a message came from the host itself, such as its own broadcast.
+    /// Another instance on the same host has its own port, so it is not taken for this one.
+    fn is_own(&self, addr: SocketAddr) -> Result<bool, NetworkError> {
+        if addr.port() != self.socket.local_addr()?.port() {
+            return Ok(false);
+        }
+        let local = self.socket.local_ips()?;
+        Ok(local.into_iter().any(|ip| is_same_ip(ip, addr.ip())))
+    }
+
+    fn check_not_self(&self, addr: SocketAddr) -> Result<(), NetworkError> {
+        if is_self_address(addr, self.socket.local_addr()?) {
+            return Err(NetworkError::SelfMessage(addr));
         }
+        Ok(())
+    }
+}
+
+/// Run the future on a current-thread runtime, then shut the runtime down. Tasks the future spawned
+/// and left running are aborted rather than awaited, so a disconnect returns promptly.
+fn block_on(future: impl Future<Output = ()>) {
+    let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed building the Runtime");
+    runtime.block_on(future);
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+}
+
+/// Resolve a `host:port` name to the first address it points at.
+async fn resolve(host: &str) -> Result<SocketAddr, NetworkError> {
+    let resolve_error = |error| NetworkError::ResolveError(host.to_string(), error);
+    lookup_host(host)
+        .await
+        .map_err(resolve_error)?
+        .next()
+        .ok_or_else(|| resolve_error(Error::new(ErrorKind::NotFound, "no address found")))
+}
+
+/// Check if the address points back at the host bound to the local address.
+fn is_self_address(addr: SocketAddr, local: SocketAddr) -> bool {
+    let ip = addr.ip().to_canonical();
+    addr.port() == local.port()
+        && (is_same_ip(ip, local.ip()) || ip.is_loopback() || ip.is_unspecified())
+}
+
+/// Compare addresses, treating IPv4-mapped IPv6 addresses as the IPv4 address they carry.
+fn is_same_ip(a: IpAddr, b: IpAddr) -> bool {
+    a.to_canonical() == b.to_canonical()
+}
+
+/// Wait for a host to be found over multicast DNS, forever if it is not used.
+async fn next_host(mdns: Option<&Mdns>) -> Vec<SocketAddr> {
+    match mdns {
+        Some(mdns) => mdns.next_host().await,
+        None => std::future::pending().await,
     }
 }
 
@@ -164,3 +1175,763 @@ async fn send_event(sender: &Sender<Event>, event: Event) {
         error!("Failed to send error event: {send_error}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::super::ECHO_ADDRESS;
+    use super::*;
+
+    static SUITE: Suite = Suite::P256Aes256Cbc;
+
+    fn message() -> UserMessage {
+        UserMessage::try_from("message".to_string()).unwrap()
+    }
+
+    fn peer_addr() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 12345))
+    }
+
+    fn loopback_task() -> (NetworkTask<Loopback>, Receiver<Event>) {
+        let (_, action) = channel(1);
+        let (event, receiver) = channel(8);
+        let name = Username::new("test".to_string()).unwrap();
+        let task = NetworkTask::new(
+            action,
+            event,
+            name,
+            PeerList::default(),
+            Context::default(),
+            Loopback::new(),
+            NetworkOptions::default(),
+        );
+        (task, receiver)
+    }
+
+    async fn socket_task(ip: IpAddr, name: &str) -> (NetworkTask<OTMPSocket>, Receiver<Event>) {
+        let (_, action) = channel(1);
+        let (event, receiver) = channel(8);
+        let name = Username::new(name.to_string()).unwrap();
+        let socket = OTMPSocket::bind_to(ip, 0).await.unwrap();
+        let task = NetworkTask::new(
+            action,
+            event,
+            name,
+            PeerList::default(),
+            Context::default(),
+            socket,
+            NetworkOptions::default(),
+        );
+        (task, receiver)
+    }
+
+    #[tokio::test]
+    async fn stray_data_is_ignored() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+
+        let result = task
+            .on_packet(Message::Data(SUITE, vec![1], vec![2]), addr)
+            .await;
+
+        assert!(result.is_ok());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn repeated_response_is_reported_once() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let response = |name: &str| {
+            let name = Username::new(name.to_string()).unwrap();
+            Message::BroadcastResponse(name, None, SUPPORTED_SUITES.to_vec())
+        };
+
+        task.on_packet(response("bob"), addr).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Connected(_))));
+
+        task.on_packet(response("bob"), addr).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        task.on_packet(response("robert"), addr).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::PeerRenamed(a, name)) if a == addr && &*name == "robert"
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_without_common_suite_fails() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+        let name = Username::new("bob".to_string()).unwrap();
+
+        task.on_packet(Message::BroadcastResponse(name, None, Vec::new()), addr)
+            .await
+            .unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Connected(_))));
+
+        let send = Action::Send(MessageId(0), addr, message(), message(), None);
+        task.on_action(send).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::SendFailed(
+                _,
+                NetworkError::CryptoError(_, CryptoError::NoCommonSuite)
+            ))
+        ));
+        assert!(!task.states.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn send_is_rejected_during_session() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+        let send = |id| Action::Send(MessageId(id), addr, message(), message(), None);
+
+        task.on_action(send(0)).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        task.on_action(send(1)).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::SendFailed(
+                MessageId(1),
+                NetworkError::SessionInFlight(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn queued_messages_wait_for_session() {
+        let (mut task, mut events) = loopback_task();
+        task.options.max_sessions = 2;
+        let send = |id| Action::Send(MessageId(id), ECHO_ADDRESS, message(), message(), None);
+
+        for id in 0..3 {
+            task.on_action(send(id)).await.unwrap();
+        }
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::SendFailed(
+                MessageId(2),
+                NetworkError::SessionInFlight(_)
+            ))
+        ));
+
+        let (response, addr) = task.socket.recv_from().await.unwrap();
+        task.on_packet(response, addr).await.unwrap();
+        let mut delivered = false;
+        while let Ok(event) = events.try_recv() {
+            delivered |= matches!(event, Event::Delivered(MessageId(0)));
+        }
+        assert!(delivered);
+        assert_eq!(task.sessions[&ECHO_ADDRESS].0, MessageId(1));
+        assert!(task.queued.is_empty());
+    }
+
+    #[tokio::test]
+    async fn greet_during_outgoing_session_is_rejected() {
+        let (mut task, mut events) = loopback_task();
+        let send = Action::Send(MessageId(0), ECHO_ADDRESS, message(), message(), None);
+        task.on_action(send).await.unwrap();
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        task.on_packet(Message::Greet(point), ECHO_ADDRESS)
+            .await
+            .unwrap();
+        assert!(task.rejected.contains_key(&ECHO_ADDRESS));
+        assert!(events.try_recv().is_err());
+
+        // The greetings crossed, so the peer rejects ours as well.
+        task.on_packet(Message::Reject(RejectReason::Busy), ECHO_ADDRESS)
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::SendFailed(MessageId(0), NetworkError::PeerBusy(_)))
+        ));
+        assert!(task.states.is_empty());
+    }
+
+    #[tokio::test]
+    async fn greet_waits_for_choice() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        task.on_packet(Message::Greet(point), addr).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::ChoiceRequested(a)) if a == addr));
+        assert!(task.states.is_empty());
+
+        // The loopback peer did not send the greet, so it rejects the response.
+        let _ = task.on_action(Action::Choose(addr, true)).await;
+        assert!(matches!(
+            task.states.get(&addr),
+            Some(MessageState::GreetReceived(_, true, _, _))
+        ));
+        assert!(task.choices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn default_choice_answers_greet() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        task.on_action(Action::DefaultChoice(Some(true)))
+            .await
+            .unwrap();
+        // The loopback peer did not send the greet, so it rejects the response.
+        let _ = task.on_packet(Message::Greet(point), addr).await;
+        assert!(events.try_recv().is_err());
+        assert!(matches!(
+            task.states.get(&addr),
+            Some(MessageState::GreetReceived(_, true, _, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scalar_is_reported_only_when_exported() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        let _ = task.answer_greet(addr, point, false).await;
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, Event::Scalar(..)));
+        }
+
+        task.on_action(Action::ExportSecrets(true)).await.unwrap();
+        let _ = task.answer_greet(addr, point, false).await;
+        let Ok(Event::Scalar(_, b)) = events.try_recv() else {
+            panic!("scalar was not reported");
+        };
+        let Some(MessageState::GreetReceived(_, _, _, response)) = task.states.get(&addr) else {
+            panic!("session was not started");
+        };
+        let b: [u8; 32] = b.try_into().unwrap();
+        let b = Scalar::from_repr(b.into()).unwrap();
+        assert_eq!(CurvePoint::GENERATOR * b, *response);
+    }
+
+    #[tokio::test]
+    async fn session_resumes_when_peer_reappears() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+        let send = Action::Send(MessageId(0), addr, message(), message(), None);
+        let name = Username::new("bob".to_string()).unwrap();
+        let bob = Message::BroadcastResponse(name, None, SUPPORTED_SUITES.to_vec());
+
+        task.on_action(send).await.unwrap();
+        task.on_packet(bob, addr).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Connected(_))));
+
+        // The loopback peer answered both the original and the resent greet.
+        for _ in 0..2 {
+            let (message, _) = task.socket.recv_from().await.unwrap();
+            assert!(matches!(message, Message::Response(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_data_is_dropped() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let (point, sender) = MessageState::send_message(message(), message(), None);
+        let (response, receiver) = MessageState::on_greeting(point, false);
+        let (m0, m1, _) = sender.on_response(SUITE, response).unwrap();
+
+        task.states.insert(addr, receiver);
+        let data = || Message::Data(SUITE, m0.clone(), m1.clone());
+        task.on_packet(data(), addr).await.unwrap();
+        let Ok(Event::Ciphertext(_, c0, c1)) = events.try_recv() else {
+            panic!("ciphertext is reported");
+        };
+        assert_eq!((c0, c1), (m0.clone(), m1.clone()));
+        assert!(matches!(events.try_recv(), Ok(Event::Message(..))));
+        assert!(matches!(events.try_recv(), Ok(Event::Transcript(..))));
+
+        // A copy arriving during the next session does not consume its state.
+        let (_, next) = MessageState::on_greeting(point, true);
+        task.states.insert(addr, next);
+        task.on_packet(data(), addr).await.unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(task.states.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn response_reports_points() {
+        let (mut task, mut events) = loopback_task();
+        let send = Action::Send(MessageId(0), ECHO_ADDRESS, message(), message(), None);
+
+        task.on_action(send).await.unwrap();
+        let Some(MessageState::GreetSent(_, point, _, _)) = task.states.get(&ECHO_ADDRESS) else {
+            panic!("greet is sent");
+        };
+        let greet = point_to_bytes(*point);
+        let (message, addr) = task.socket.recv_from().await.unwrap();
+        let Message::Response(response) = message else {
+            panic!("loopback answers the greet");
+        };
+        task.on_packet(message, addr).await.unwrap();
+        let Ok(Event::Points(_, sent, received)) = events.try_recv() else {
+            panic!("points are reported");
+        };
+        assert_eq!(sent, greet);
+        assert_eq!(received, point_to_bytes(response));
+        assert!(matches!(events.try_recv(), Ok(Event::Sas(..))));
+        assert!(matches!(events.try_recv(), Ok(Event::Ciphertext(..))));
+        assert!(matches!(events.try_recv(), Ok(Event::Transcript(..))));
+    }
+
+    #[tokio::test]
+    async fn replayed_data_keeps_session() {
+        let (mut task, _events) = loopback_task();
+        let addr = peer_addr();
+        let (point, sender) = MessageState::send_message(message(), message(), None);
+        let (response, receiver) = MessageState::on_greeting(point, false);
+        let (m0, m1, _) = sender.on_response(SUITE, response).unwrap();
+
+        task.states.insert(addr, receiver);
+        task.on_data(addr, SUITE, m0.clone(), m1.clone(), false)
+            .await
+            .unwrap();
+
+        let (point, _) = MessageState::send_message(message(), message(), None);
+        let (_, receiver) = MessageState::on_greeting(point, false);
+        task.states.insert(addr, receiver);
+        let result = task.on_data(addr, SUITE, m0, m1, false).await;
+
+        assert!(matches!(
+            result,
+            Err(NetworkError::CryptoError(_, CryptoError::Replay))
+        ));
+        assert!(task.states.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn data_under_other_key_fails_to_decrypt() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let (point, sender) = MessageState::send_message(message(), message(), None);
+        let (response, receiver) = MessageState::on_greeting(point, false);
+        let (m0, m1, _) = sender.on_response(SUITE, response).unwrap();
+
+        // The second message belongs to this session, but under the key that was not chosen.
+        task.states.insert(addr, receiver);
+        task.on_packet(Message::Data(SUITE, m1, m0), addr)
+            .await
+            .unwrap();
+
+        assert!(matches!(events.try_recv(), Ok(Event::Ciphertext(..))));
+        assert!(matches!(events.try_recv(), Ok(Event::DecryptFailed(_))));
+        assert!(!task.states.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn repeated_greet_asks_once() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        task.on_packet(Message::Greet(point), addr).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::ChoiceRequested(_))));
+
+        task.on_packet(Message::Greet(point), addr).await.unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn refresh_prunes_silent_peers() {
+        let (mut task, mut events) = loopback_task();
+        let bob = peer_addr();
+        let carol = SocketAddr::from(([10, 0, 0, 2], 12345));
+        let name = |name: &str| Username::new(name.to_string()).unwrap();
+        let seen = Instant::now() - Duration::from_secs(1);
+        for (addr, peer) in [(bob, "bob"), (carol, "carol")] {
+            let peer = Peer::new_with_name(addr, name(peer));
+            task.peers.lock().unwrap().insert(addr, (peer, seen));
+        }
+
+        task.on_action(Action::Broadcast).await.unwrap();
+        let response = Message::BroadcastResponse(name("bob"), None, SUPPORTED_SUITES.to_vec());
+        task.on_packet(response, bob).await.unwrap();
+        assert_eq!(task.peers.lock().unwrap().len(), 2);
+
+        task.prune_peers().await;
+        assert!(matches!(events.try_recv(), Ok(Event::Disconnected(a)) if a == carol));
+        assert!(matches!(events.try_recv(), Ok(Event::Refreshed)));
+        assert!(task.peers.lock().unwrap().contains_key(&bob));
+        assert!(task.refresh.is_none());
+    }
+
+    #[tokio::test]
+    async fn rename_greets_known_peers() {
+        let (mut task, _events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+        let name = |name: &str| Username::new(name.to_string()).unwrap();
+        let peer = Peer::new_with_name(addr, name("Echo"));
+        task.peers
+            .lock()
+            .unwrap()
+            .insert(addr, (peer, Instant::now()));
+
+        task.on_action(Action::SetName(name("renamed")))
+            .await
+            .unwrap();
+        assert!(matches!(
+            task.greet_message(),
+            Message::BroadcastGreet(name, ..) if &*name == "renamed"
+        ));
+
+        // The loopback peer answered both the direct greet and the broadcast.
+        for _ in 0..2 {
+            let (message, _) = task.socket.recv_from().await.unwrap();
+            assert!(matches!(message, Message::BroadcastResponse(..)));
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_greets_are_answered_once_after_delay() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let greet = || {
+            let name = Username::new("bob".to_string()).unwrap();
+            Message::BroadcastGreet(name, None, SUPPORTED_SUITES.to_vec())
+        };
+
+        task.on_packet(greet(), addr).await.unwrap();
+        task.on_packet(greet(), addr).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Connected(_))));
+        assert_eq!(task.responses.len(), 1);
+        let due = task.responses[&addr];
+        assert!(due <= Instant::now() + RESPONSE_JITTER);
+
+        task.send_responses(due).await.unwrap();
+        assert!(task.responses.is_empty());
+
+        // Once answered, the next greeting is answered again.
+        task.on_packet(greet(), addr).await.unwrap();
+        assert!(task.responses.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn cancel_send_drops_unanswered_message() {
+        let (mut task, mut events) = loopback_task();
+        let send = Action::Send(MessageId(0), ECHO_ADDRESS, message(), message(), None);
+        task.on_action(send).await.unwrap();
+
+        task.on_action(Action::CancelSend(MessageId(1)))
+            .await
+            .unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(task.sessions.contains_key(&ECHO_ADDRESS));
+
+        task.on_action(Action::CancelSend(MessageId(0)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::SendFailed(
+                MessageId(0),
+                NetworkError::SessionCancelled(_)
+            ))
+        ));
+        assert!(task.states.is_empty());
+
+        // The answer of the peer comes too late to deliver the message.
+        let (message, addr) = task.socket.recv_from().await.unwrap();
+        task.on_packet(message, addr).await.unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn directed_discovery_greets_known_peers_only() {
+        let (mut task, _events) = loopback_task();
+        task.options.discovery = Discovery::Directed;
+
+        task.on_action(Action::Broadcast).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_millis(50), task.socket.recv_from());
+        assert!(received.await.is_err());
+
+        let peer = Peer::new(ECHO_ADDRESS);
+        task.peers
+            .lock()
+            .unwrap()
+            .insert(ECHO_ADDRESS, (peer, Instant::now()));
+        task.on_action(Action::Heartbeat).await.unwrap();
+        let (message, addr) = task.socket.recv_from().await.unwrap();
+        assert!(matches!(message, Message::BroadcastResponse(..)));
+        assert_eq!(addr, ECHO_ADDRESS);
+    }
+
+    #[tokio::test]
+    async fn host_found_over_mdns_is_greeted_once() {
+        let (mut task, _events) = loopback_task();
+        task.options.discovery = Discovery::Mdns;
+
+        task.on_host_found(vec![ECHO_ADDRESS]).await.unwrap();
+        let (message, addr) = task.socket.recv_from().await.unwrap();
+        assert!(matches!(message, Message::BroadcastResponse(..)));
+        assert_eq!(addr, ECHO_ADDRESS);
+
+        let own = task.socket.local_addr().unwrap();
+        task.on_host_found(vec![own]).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_millis(50), task.socket.recv_from());
+        assert!(received.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_is_sent_once_per_interval() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+
+        for _ in 0..3 {
+            task.reject(addr, RejectReason::InvalidName).await.unwrap();
+        }
+        assert_eq!(task.rejected.len(), 1);
+
+        task.on_packet(Message::Reject(RejectReason::InvalidName), addr)
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::Rejected(a, RejectReason::InvalidName)) if a == addr
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancel_peer_drops_sessions() {
+        let (mut task, mut events) = loopback_task();
+        let bob = SocketAddr::from(([127, 0, 0, 2], 1));
+        let carol = SocketAddr::from(([10, 0, 0, 2], 12345));
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        let send = Action::Send(MessageId(0), bob, message(), message(), None);
+        task.on_action(send).await.unwrap();
+        task.on_packet(Message::Greet(point), carol).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::ChoiceRequested(_))));
+
+        task.on_action(Action::CancelPeer(bob)).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::SendFailed(MessageId(0), NetworkError::SessionCancelled(a))) if a == bob
+        ));
+        assert!(!task.states.contains_key(&bob));
+        assert!(!task.sessions.contains_key(&bob));
+        assert!(task.choices.contains_key(&carol));
+
+        task.on_action(Action::CancelPeer(carol)).await.unwrap();
+        assert!(task.choices.is_empty());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn blocked_peers_are_dropped() {
+        let (mut task, mut events) = loopback_task();
+        let bob = peer_addr();
+        let carol = SocketAddr::from(([10, 0, 0, 2], 12345));
+        let name = |name: &str| Username::new(name.to_string()).unwrap();
+        let hello = |name| Message::BroadcastResponse(name, None, SUPPORTED_SUITES.to_vec());
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        task.on_packet(hello(name("bob")), bob).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Connected(_))));
+
+        let blocklist = Action::SetBlocklist([carol.ip()].into(), [name("bob")].into());
+        task.on_action(blocklist).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Disconnected(addr)) if addr == bob));
+
+        // Bob is blocked by name, Carol by address under any name.
+        task.on_packet(hello(name("bob")), bob).await.unwrap();
+        task.on_packet(hello(name("dave")), carol).await.unwrap();
+        task.on_packet(Message::Greet(point), carol).await.unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(task.peers.lock().unwrap().is_empty());
+        assert!(task.choices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stray_response_keeps_incoming_session() {
+        let (mut task, mut events) = loopback_task();
+        let addr = peer_addr();
+        let (point, _) = MessageState::send_message(message(), message(), None);
+        let (response, receiver) = MessageState::on_greeting(point, false);
+        task.states.insert(addr, receiver);
+
+        task.on_packet(Message::Response(response), addr)
+            .await
+            .unwrap();
+        assert!(matches!(
+            task.states.get(&addr),
+            Some(MessageState::GreetReceived(..))
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn late_response_to_cancelled_session_is_ignored() {
+        let (mut task, mut events) = loopback_task();
+        let send = Action::Send(MessageId(0), ECHO_ADDRESS, message(), message(), None);
+        let (point, _) = MessageState::send_message(message(), message(), None);
+
+        task.on_action(send).await.unwrap();
+        task.on_action(Action::CancelPeer(ECHO_ADDRESS))
+            .await
+            .unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::SendFailed(..))));
+
+        // The peer starts its own transfer before the response to the cancelled one arrives.
+        let (_, receiver) = MessageState::on_greeting(point, false);
+        task.states.insert(ECHO_ADDRESS, receiver);
+        let (response, addr) = task.socket.recv_from().await.unwrap();
+        assert!(matches!(response, Message::Response(_)));
+        task.on_packet(response, addr).await.unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(matches!(
+            task.states.get(&ECHO_ADDRESS),
+            Some(MessageState::GreetReceived(..))
+        ));
+    }
+
+    #[test]
+    fn shutdown_aborts_spawned_tasks() {
+        let started = Instant::now();
+        block_on(async {
+            tokio::spawn(std::future::pending::<()>());
+            tokio::spawn(tokio::time::sleep(Duration::from_secs(60)));
+        });
+        assert!(started.elapsed() < SHUTDOWN_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn ping_reports_round_trip() {
+        let (mut task, mut events) = loopback_task();
+        let addr = SocketAddr::from(([127, 0, 0, 2], 1));
+
+        task.on_action(Action::Ping(addr)).await.unwrap();
+        let (pong, sender) = task.socket.recv_from().await.unwrap();
+        task.on_packet(Message::Pong(0), sender).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        task.on_packet(pong, sender).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Pong(a, _)) if a == addr));
+        assert!(task.pings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn announcement_is_reported() {
+        let (mut task, mut events) = loopback_task();
+        let text = UserMessage::try_from("hello everyone".to_string()).unwrap();
+
+        task.on_action(Action::Announce(text)).await.unwrap();
+        let (announcement, sender) = task.socket.recv_from().await.unwrap();
+        task.on_packet(announcement, sender).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::Announcement(a, text)) if a == ECHO_ADDRESS && text == "hello everyone"
+        ));
+    }
+
+    #[tokio::test]
+    async fn own_broadcast_from_any_interface_is_dropped() {
+        let (mut task, mut events) = socket_task(IpAddr::from([0, 0, 0, 0]), "test").await;
+        let port = task.socket.local_addr().unwrap().port();
+
+        // Bound to all interfaces, the loopback one is local even if it is not the local IP.
+        let own = SocketAddr::from(([127, 0, 0, 1], port));
+        let name = Username::new("test".to_string()).unwrap();
+        let greet = Message::BroadcastGreet(name, None, SUPPORTED_SUITES.to_vec());
+        task.on_packet(greet, own).await.unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(task.peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_host_on_other_port_is_not_own() {
+        let (mut task, mut events) = socket_task(IpAddr::from([127, 0, 0, 1]), "test").await;
+        let own = task.socket.local_addr().unwrap();
+        let other = SocketAddr::new(own.ip(), own.port() ^ 1);
+        let greet = || {
+            let name = Username::new("other".to_string()).unwrap();
+            Message::BroadcastGreet(name, None, SUPPORTED_SUITES.to_vec())
+        };
+
+        task.on_packet(greet(), own).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        task.on_packet(greet(), other).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::Connected(peer)) if peer.address() == other
+        ));
+        assert!(!task.peers.lock().unwrap().contains_key(&own));
+    }
+
+    #[tokio::test]
+    async fn profiles_on_one_host_discover_each_other() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let (mut alice, mut alice_events) = socket_task(ip, "alice").await;
+        let (mut bob, mut bob_events) = socket_task(ip, "bob").await;
+        let alice_addr = alice.socket.local_addr().unwrap();
+        let bob_addr = bob.socket.local_addr().unwrap();
+
+        alice.on_action(Action::Greet(bob_addr)).await.unwrap();
+        let (message, addr) = bob.socket.recv_from().await.unwrap();
+        assert_eq!(addr, alice_addr);
+        bob.on_packet(message, addr).await.unwrap();
+        assert!(matches!(
+            bob_events.try_recv(),
+            Ok(Event::Connected(peer)) if peer.address() == alice_addr
+        ));
+
+        bob.send_responses(Instant::now() + RESPONSE_JITTER)
+            .await
+            .unwrap();
+        let (message, addr) = alice.socket.recv_from().await.unwrap();
+        alice.on_packet(message, addr).await.unwrap();
+        assert!(matches!(
+            alice_events.try_recv(),
+            Ok(Event::Connected(peer)) if peer.address() == bob_addr
+        ));
+    }
+
+    #[test]
+    fn mapped_address_is_same_ip() {
+        let ip = IpAddr::from([192, 168, 1, 10]);
+        let mapped = IpAddr::from([0, 0, 0, 0, 0, 0xFFFF, 0xC0A8, 0x010A]);
+
+        assert!(is_same_ip(ip, mapped));
+        assert!(is_same_ip(mapped, ip));
+        assert!(!is_same_ip(IpAddr::from([192, 168, 1, 11]), mapped));
+        assert!(is_self_address(
+            SocketAddr::new(mapped, 12345),
+            SocketAddr::new(ip, 12345)
+        ));
+    }
+
+    #[test]
+    fn self_address_detection() {
+        let local = SocketAddr::from(([192, 168, 1, 10], 12345));
+
+        assert!(is_self_address(local, local));
+        assert!(is_self_address(
+            SocketAddr::from(([127, 0, 0, 1], 12345)),
+            local
+        ));
+        assert!(is_self_address(
+            SocketAddr::from(([0, 0, 0, 0], 12345)),
+            local
+        ));
+        assert!(!is_self_address(
+            SocketAddr::from(([192, 168, 1, 11], 12345)),
+            local
+        ));
+        assert!(!is_self_address(
+            SocketAddr::from(([192, 168, 1, 10], 12346)),
+            local
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+//! Builds the crate with each user interface alone, guarding the cfg gating between `gui` and `tui`.
+//!
+//! The builds run a separate cargo in their own target directory. Checking `tui` alone and
+//! rejecting both interfaces run by default, the slower `gui` build and dependency tree are
+//! ignored. Run them with `cargo test --test features -- --ignored`.
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+static GUI_CRATES: &[&str] = &["eframe", "egui", "egui_tiles", "egui-toast"];
+
+fn cargo(args: &[&str], features: &str) -> Output {
+    let target_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("features");
+    Command::new(env!("CARGO"))
+        .args(args)
+        .args(["--no-default-features", "--features", features])
+        .env("CARGO_TARGET_DIR", target_dir)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cargo runs")
+}
+
+fn check(features: &str) -> Output {
+    cargo(&["check", "--lib"], features)
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn tui_source_does_not_use_gui() {
+    let source = include_str!("../src/tui/mod.rs");
+    for name in ["eframe", "egui"] {
+        assert!(!source.contains(name), "tui refers to {name}");
+    }
+}
+
+#[test]
+fn tui_builds_alone() {
+    assert_success(&check("tui"));
+    assert_success(&check("tui,cli"));
+}
+
+#[test]
+#[ignore = "runs a separate cargo build"]
+fn gui_builds_alone() {
+    assert_success(&check("gui"));
+}
+
+#[test]
+fn gui_and_tui_are_rejected() {
+    let output = check("gui,tui");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("mutually exclusive"));
+}
+
+#[test]
+#[ignore = "runs a separate cargo build"]
+fn tui_does_not_depend_on_gui_crates() {
+    let output = cargo(
+        &["tree", "--edges", "normal", "--prefix", "none"],
+        "tui,cli",
+    );
+    assert_success(&output);
+    let tree = String::from_utf8_lossy(&output.stdout);
+    for name in GUI_CRATES {
+        let prefix = format!("{name} v");
+        assert!(
+            !tree.lines().any(|line| line.starts_with(&prefix)),
+            "tui pulls in {name}"
+        );
+    }
+}